@@ -1,4 +1,5 @@
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+use std::fmt;
 use std::sync::{Arc, Mutex, RwLock};
 
 use serde_json::Value;
@@ -11,6 +12,33 @@ pub fn new_arc_mutex<T>(t: T) -> Arc<Mutex<T>> {
     Arc::new(Mutex::new(t))
 }
 
+// delivery acknowledgement of a record, called once its sink accepted it
+#[derive(Clone)]
+pub struct Ack(Arc<dyn Fn() + Send + Sync>);
+
+impl Ack {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    pub fn none() -> Self {
+        Self::new(|| {})
+    }
+
+    pub fn ack(&self) {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for Ack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Ack")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Item {
     JSON(Value),
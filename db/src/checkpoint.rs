@@ -0,0 +1,117 @@
+use super::Pod;
+use common::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct Checkpoint {
+    pub dev: u64,
+    pub inode: u64,
+    pub offset: i64,
+}
+
+// durable path -> (dev, inode, offset) registry, survives agent restarts
+pub struct CheckpointStore {
+    path: PathBuf,
+    entries: HashMap<String, Checkpoint>,
+}
+
+impl CheckpointStore {
+    pub fn load(path: &str) -> Result<Self> {
+        let path = PathBuf::from(path);
+        let entries = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(Box::new(e)),
+        };
+        Ok(Self { path, entries })
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Checkpoint> {
+        self.entries.get(path)
+    }
+
+    pub fn restore(&self, pod: &mut Pod) {
+        if let Some(cp) = self.get(&pod.path) {
+            pod.dev = cp.dev;
+            pod.inode = cp.inode;
+            pod.offset = cp.offset;
+        }
+    }
+
+    pub fn save(&mut self, pods: &[Pod]) -> Result<()> {
+        // pods never opened have no file identity yet, keep what was loaded for them
+        for pod in pods.iter().filter(|pod| pod.inode != 0) {
+            self.entries.insert(
+                pod.path.clone(),
+                Checkpoint {
+                    dev: pod.dev,
+                    inode: pod.inode,
+                    offset: pod.offset,
+                },
+            );
+        }
+        self.entries.retain(|path, _| fs::metadata(path).is_ok());
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // write aside then rename, a crash never leaves a half written registry
+        let tmp = self.path.with_extension("tmp");
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut w, &self.entries)?;
+        w.flush()?;
+        w.get_ref().sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_works() {
+        let dir = std::env::temp_dir().join("harvest_db_checkpoint");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("0.log").to_str().unwrap().to_string();
+        fs::write(&log, "abc\n").unwrap();
+        let registry = dir.join("registry/checkpoint.json");
+
+        let mut store = CheckpointStore::load(registry.to_str().unwrap()).unwrap();
+        assert_eq!(store.get(&log), None);
+        store
+            .save(&[
+                Pod {
+                    path: log.clone(),
+                    dev: 1,
+                    inode: 2,
+                    offset: 4,
+                    ..Default::default()
+                },
+                // removed file
+                Pod {
+                    path: dir.join("1.log").to_str().unwrap().to_string(),
+                    inode: 3,
+                    ..Default::default()
+                },
+            ])
+            .unwrap();
+
+        let store = CheckpointStore::load(registry.to_str().unwrap()).unwrap();
+        let mut pod = Pod {
+            path: log.clone(),
+            ..Default::default()
+        };
+        store.restore(&mut pod);
+        assert_eq!((pod.dev, pod.inode, pod.offset), (1, 2, 4));
+        assert_eq!(store.entries.len(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
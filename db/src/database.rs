@@ -16,6 +16,14 @@ pub enum Event {
     Delete,
     #[strum(serialize = "offset")]
     IncrOffset,
+    #[strum(serialize = "commit")]
+    CommitOffset,
+    #[strum(serialize = "rotate")]
+    Rotate,
+    #[strum(serialize = "truncate")]
+    Truncate,
+    #[strum(serialize = "track")]
+    Track,
     #[strum(serialize = "close")]
     Close,
 }
@@ -37,6 +45,8 @@ enum ListenerEvent {
     Open,
     #[strum(serialize = "close")]
     Close,
+    #[strum(serialize = "truncate")]
+    Truncate,
 }
 
 pub struct MemDatabaseEventDispatcher {
@@ -64,6 +74,14 @@ impl MemDatabaseEventDispatcher {
         self.dispatchers.registry(ListenerEvent::Close.as_ref(), l)
     }
 
+    pub(crate) fn registry_truncate_event_listener<L>(&mut self, l: L)
+    where
+        L: Listener<Pod> + Send + Sync + 'static,
+    {
+        self.dispatchers
+            .registry(ListenerEvent::Truncate.as_ref(), l)
+    }
+
     pub(crate) fn dispatch_open_event(&mut self, pod: &Pod) {
         self.dispatchers.dispatch(ListenerEvent::Open.as_ref(), pod)
     }
@@ -72,6 +90,11 @@ impl MemDatabaseEventDispatcher {
         self.dispatchers
             .dispatch(ListenerEvent::Close.as_ref(), pod)
     }
+
+    pub(crate) fn dispatch_truncate_event(&mut self, pod: &Pod) {
+        self.dispatchers
+            .dispatch(ListenerEvent::Truncate.as_ref(), pod)
+    }
 }
 
 pub struct MemDatabase {
@@ -141,6 +164,56 @@ impl MemDatabase {
                         };
                     }
 
+                    Event::CommitOffset => {
+                        if let Some(inner) = m.get_mut(&pod.path) {
+                            // the tail commits the acknowledged prefix, acks of a rotated or
+                            // truncated file (older epoch) never move the offset
+                            if inner.dev == pod.dev
+                                && inner.inode == pod.inode
+                                && inner.epoch == pod.epoch
+                                && pod.offset > inner.offset
+                            {
+                                inner.last_offset = pod.offset - inner.offset;
+                                inner.offset = pod.offset;
+                            }
+                        };
+                    }
+
+                    Event::Rotate => {
+                        if let Some(inner) = m.get_mut(&pod.path) {
+                            inner.dev = pod.dev;
+                            inner.inode = pod.inode;
+                            inner.last_offset = 0;
+                            inner.offset = 0;
+                        };
+                    }
+
+                    Event::Truncate => {
+                        if let Some(inner) = m.get_mut(&pod.path) {
+                            inner.epoch = pod.epoch;
+                            // listeners see the offset the file shrank below
+                            let truncated = Pod {
+                                offset: pod.offset,
+                                ..inner.clone()
+                            };
+                            inner.last_offset = 0;
+                            inner.offset = 0;
+                            match t_dispatchers.write() {
+                                Ok(mut dispatch) => dispatch.dispatch_truncate_event(&truncated),
+                                Err(e) => {
+                                    eprintln!("MemDatabase thread dispath truncate event failed, error:{:?}",e)
+                                }
+                            }
+                        };
+                    }
+
+                    Event::Track => {
+                        // like update, the tail already runs so no open is dispatched
+                        let inner = m.entry(pod.path.to_string()).or_insert(pod.clone());
+                        inner.merge_with(&pod);
+                        inner.epoch = pod.epoch;
+                    }
+
                     Event::Close => {
                         break;
                     }
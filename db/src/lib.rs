@@ -1,11 +1,18 @@
 #[macro_use]
 extern crate lazy_static;
+mod checkpoint;
 mod database;
 
 mod pod;
+use async_std::task;
 use database::Message;
 use event::Listener;
-pub use pod::{GetPod, Pod, PodList, PodListMarshaller, State};
+pub use pod::{channels, GetPod, LogFormat, Multiline, Pod, PodList, PodListMarshaller, State};
+use std::sync::RwLock;
+use std::time::Duration;
+
+pub use checkpoint::{Checkpoint, CheckpointStore};
+use common::Result;
 
 pub use common::new_arc_rwlock;
 pub use database::Event;
@@ -16,6 +23,46 @@ lazy_static! {
         let m = MemDatabase::new(new_arc_rwlock(MemDatabaseEventDispatcher::new()));
         m
     };
+    static ref CHECKPOINT: RwLock<Option<CheckpointStore>> = RwLock::new(None);
+}
+
+// load the checkpoint registry and flush the collected offsets to it every interval
+pub fn open_checkpoint(path: &str, interval: Duration) -> Result<()> {
+    let store = CheckpointStore::load(path)?;
+    match CHECKPOINT.write() {
+        Ok(mut cp) => *cp = Some(store),
+        Err(e) => return Err(format!("{:?}", e).into()),
+    }
+    task::spawn(async move {
+        loop {
+            task::sleep(interval).await;
+            if let Err(e) = checkpoint() {
+                eprintln!("checkpoint flush failed, error: {:?}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+// flush the offsets of all pods to the checkpoint registry
+pub fn checkpoint() -> Result<()> {
+    let pods = all_to_json().0;
+    match CHECKPOINT.write() {
+        Ok(mut cp) => match cp.as_mut() {
+            Some(store) => store.save(&pods),
+            None => Ok(()),
+        },
+        Err(e) => Err(format!("{:?}", e).into()),
+    }
+}
+
+// fill in the offset and file identity recorded before the last restart
+pub fn restore_offset(pod: &mut Pod) {
+    if let Ok(cp) = CHECKPOINT.read() {
+        if let Some(store) = cp.as_ref() {
+            store.restore(pod);
+        }
+    }
 }
 
 pub fn incr_offset(uuid: &str, offset: i64) {
@@ -31,6 +78,68 @@ pub fn incr_offset(uuid: &str, offset: i64) {
         .unwrap()
 }
 
+// the output acknowledged everything of the file (dev, inode, epoch) up to pod.offset
+pub fn commit_offset(pod: &Pod) {
+    MEM.tx
+        .send(Message {
+            event: Event::CommitOffset,
+            pod: Pod {
+                path: pod.path.clone(),
+                dev: pod.dev,
+                inode: pod.inode,
+                epoch: pod.epoch,
+                offset: pod.offset,
+                ..Default::default()
+            },
+        })
+        .unwrap();
+}
+
+// the path now points to a new file (dev, inode), collection restarts at offset 0
+pub fn rotate(pod: &Pod) {
+    MEM.tx
+        .send(Message {
+            event: Event::Rotate,
+            pod: Pod {
+                path: pod.path.clone(),
+                dev: pod.dev,
+                inode: pod.inode,
+                ..Default::default()
+            },
+        })
+        .unwrap();
+}
+
+// the file shrank below the collected pod.offset (copytruncate), restart at offset 0
+// in a new epoch, returned and recorded so acks of the old content are ignored.
+// truncate listeners are told about it
+pub fn truncate(pod: &Pod) -> u64 {
+    let epoch = pod.epoch + 1;
+    MEM.tx
+        .send(Message {
+            event: Event::Truncate,
+            pod: Pod {
+                path: pod.path.clone(),
+                offset: pod.offset,
+                epoch,
+                ..Default::default()
+            },
+        })
+        .unwrap();
+    epoch
+}
+
+// the tail of pod.path runs on file (dev, inode, epoch) from pod.offset,
+// recorded like update without dispatching an open event again
+pub fn track(pod: &Pod) {
+    MEM.tx
+        .send(Message {
+            event: Event::Track,
+            pod: pod.clone(),
+        })
+        .unwrap();
+}
+
 pub fn update(pod: &Pod) {
     MEM.tx
         .send(Message {
@@ -163,3 +272,16 @@ where
         }
     }
 }
+
+// called with the pod, at the offset it shrank below, when a file is truncated
+pub fn registry_truncate_event_listener<L>(l: L)
+where
+    L: Listener<Pod> + Send + Sync + 'static,
+{
+    match MEM.dispatchers.write() {
+        Ok(mut dispatcher) => dispatcher.registry_truncate_event_listener(l),
+        Err(e) => {
+            eprintln!("{:?}", e)
+        }
+    }
+}
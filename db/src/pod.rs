@@ -5,6 +5,32 @@ pub enum State {
     Running,
     Stopped,
 }
+// joins the lines of one event (e.g. a stack trace) into a single record
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct Multiline {
+    // regex, a matching line starts a new record
+    #[serde(default)]
+    pub start: String,
+    // regex, a matching line is appended to the current record, e.g. `^(\s|Caused by:)`
+    #[serde(default)]
+    pub continuation: String,
+    #[serde(default)]
+    pub max_lines: usize,
+    #[serde(default)]
+    pub timeout_ms: u64,
+}
+
+// how the runtime writes the log file, set by the discovery mode that found it
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Raw,
+    // docker json-file driver, <id>-json.log next to config.v2.json
+    DockerJSON,
+    // containerd/cri-o, /var/log/pods/<ns>_<pod>_<uid>/<container>/N.log
+    CRI,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct Pod {
     pub ns: String,
@@ -12,16 +38,42 @@ pub struct Pod {
     pub pod_name: String,
     pub container: String,
     pub path: String, // on this the uuid path is unique identifier
+    #[serde(default)]
+    pub format: LogFormat,
     pub offset: i64,
+    // identity of the file the offset belongs to, changes when the path is rotated
+    pub dev: u64,
+    pub inode: u64,
+    // bumped when the file is truncated in place, acks of an older epoch are stale
+    #[serde(default)]
+    pub epoch: u64,
     pub is_upload: bool,
     pub state: State,
     pub filter: String,
+    pub multiline: Option<Multiline>,
     pub output: String,
+    // more channels the lines also go to, each behind its own lane
+    #[serde(default)]
+    pub outputs: Vec<String>,
     pub ips: Vec<String>,
     pub last_offset: i64,
     pub node_name: String,
 }
 
+// output followed by outputs, without empty or repeated channels
+pub fn channels<'a, I>(output: &'a str, outputs: I) -> Vec<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut channels: Vec<&'a str> = Vec::new();
+    for channel in std::iter::once(output).chain(outputs) {
+        if !channel.is_empty() && !channels.contains(&channel) {
+            channels.push(channel);
+        }
+    }
+    channels
+}
+
 impl Pod {
     pub fn set_state_run(&mut self) -> &mut Self {
         self.state = State::Running;
@@ -55,14 +107,28 @@ impl Pod {
     pub fn merge(&mut self, other: &Pod) -> &mut Self {
         self.is_upload = other.is_upload;
         self.filter = other.filter.clone();
+        self.multiline = other.multiline.clone();
         self.output = other.output.clone();
+        self.outputs = other.outputs.clone();
         self.offset = other.offset.clone();
+        // pods of api server tasks do not know the file format
+        if other.format != LogFormat::Raw {
+            self.format = other.format;
+        }
+        if other.inode != 0 {
+            self.dev = other.dev;
+            self.inode = other.inode;
+        }
         self.node_name = other.node_name.clone();
         self.ips = self.ips.clone();
         self.state = other.state.clone();
         self
     }
 
+    pub fn channels(&self) -> Vec<&str> {
+        channels(&self.output, self.outputs.iter().map(|o| o.as_str()))
+    }
+
     pub fn is_running(&self) -> bool {
         self.state == State::Running
     }
@@ -95,14 +161,20 @@ impl Default for Pod {
         Pod {
             service_name: "".to_string(),
             path: "".to_string(),
+            format: LogFormat::Raw,
             offset: 0,
+            dev: 0,
+            inode: 0,
+            epoch: 0,
             ns: "".to_string(),
             pod_name: "".to_string(),
             container: "".to_string(),
             is_upload: false,
             state: State::Ready,
             filter: "".to_string(),
+            multiline: None,
             output: "".to_string(),
+            outputs: Vec::new(),
             ips: Vec::new(),
             last_offset: 0,
             node_name: "".to_string(),
@@ -0,0 +1,223 @@
+use chrono::DateTime;
+use db::LogFormat;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LogLine {
+    pub message: String,
+    pub stream: String,
+    pub time: String,
+    // file offset right after the last line of this record
+    pub offset: i64,
+    // end offsets of the cri partial lines joined into this record
+    pub fragments: Vec<i64>,
+}
+
+impl LogLine {
+    pub fn raw(line: &str) -> Self {
+        Self {
+            message: line.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+// docker json-file driver envelope
+// {"log":"hello\n","stream":"stdout","time":"2021-03-16T09:05:01.461813069Z"}
+#[derive(Deserialize)]
+struct DockerJSONLine {
+    log: String,
+    #[serde(default)]
+    stream: String,
+    #[serde(default)]
+    time: String,
+}
+
+const CRI_TAG_PARTIAL: &str = "P";
+const CRI_TAG_FULL: &str = "F";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decoder {
+    Raw,
+    DockerJSON,
+    // pending partial line per stream
+    CRI(HashMap<String, LogLine>),
+}
+
+impl Decoder {
+    pub fn new(format: LogFormat) -> Self {
+        match format {
+            LogFormat::Raw => Decoder::Raw,
+            LogFormat::DockerJSON => Decoder::DockerJSON,
+            LogFormat::CRI => Decoder::CRI(HashMap::new()),
+        }
+    }
+
+    // `offset` is the file offset right after `line`, None while a partial
+    // line waits for the rest of its record
+    pub fn decode(&mut self, line: &str, offset: i64) -> Option<LogLine> {
+        if line.is_empty() {
+            return None;
+        }
+        let mut line = match self {
+            Decoder::Raw => LogLine::raw(line),
+            Decoder::DockerJSON => decode_docker_json(line),
+            Decoder::CRI(partials) => decode_cri(partials, line, offset)?,
+        };
+        line.offset = offset;
+        Some(line)
+    }
+}
+
+// <RFC3339 time> <stream> <P|F> <message>
+fn decode_cri(partials: &mut HashMap<String, LogLine>, line: &str, offset: i64) -> Option<LogLine> {
+    let fields = trim_newline(line).splitn(4, ' ').collect::<Vec<&str>>();
+    let valid = fields.len() >= 3
+        && DateTime::parse_from_rfc3339(fields[0]).is_ok()
+        && (fields[1] == "stdout" || fields[1] == "stderr")
+        && (fields[2] == CRI_TAG_PARTIAL || fields[2] == CRI_TAG_FULL);
+    if !valid {
+        eprintln!("decode cri line error, line: {:?}", line);
+        return Some(LogLine::raw(line));
+    }
+    let (time, stream, tag) = (fields[0], fields[1], fields[2]);
+    let content = fields.get(3).unwrap_or(&"");
+
+    let mut pending = partials.remove(stream).unwrap_or_else(|| LogLine {
+        stream: stream.to_string(),
+        time: time.to_string(),
+        ..Default::default()
+    });
+    pending.message.push_str(content);
+    if tag == CRI_TAG_PARTIAL {
+        pending.fragments.push(offset);
+        partials.insert(stream.to_string(), pending);
+        return None;
+    }
+    Some(pending)
+}
+
+fn decode_docker_json(line: &str) -> LogLine {
+    match serde_json::from_str::<DockerJSONLine>(line) {
+        Ok(docker_line) => LogLine {
+            message: trim_newline(&docker_line.log).to_string(),
+            stream: docker_line.stream,
+            time: docker_line.time,
+            ..Default::default()
+        },
+        Err(e) => {
+            eprintln!("decode docker json line error: {:?}, line: {:?}", e, line);
+            LogLine::raw(line)
+        }
+    }
+}
+
+fn trim_newline(s: &str) -> &str {
+    s.strip_suffix('\n')
+        .map(|s| s.strip_suffix('\r').unwrap_or(s))
+        .unwrap_or(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn docker_json_decode_works() {
+        let mut decoder = Decoder::new(LogFormat::DockerJSON);
+        assert_eq!(decoder, Decoder::DockerJSON);
+
+        let line = decoder
+            .decode(
+                r#"{"log":"hello \"world\"\n","stream":"stderr","time":"2021-03-16T09:05:01.461813069Z"}
+"#,
+                0,
+            )
+            .unwrap();
+        assert_eq!(line.message, r#"hello "world""#);
+        assert_eq!(line.stream, "stderr");
+        assert_eq!(line.time, "2021-03-16T09:05:01.461813069Z");
+    }
+
+    #[test]
+    fn raw_decode_works() {
+        let mut decoder = Decoder::new(LogFormat::Raw);
+        assert_eq!(decoder, Decoder::Raw);
+        assert_eq!(decoder.decode("", 0), None);
+        assert_eq!(decoder.decode("abc\n", 0).unwrap().message, "abc\n");
+        // a cri looking line in a file not found by cri discovery stays as it is
+        let line = "2021-03-16T09:05:01.461813069Z stdout F hello\n";
+        assert_eq!(decoder.decode(line, 0).unwrap().message, line);
+
+        // a broken docker line is kept as is instead of being dropped
+        let mut decoder = Decoder::DockerJSON;
+        assert_eq!(decoder.decode("not json", 0).unwrap().message, "not json");
+    }
+
+    #[test]
+    fn cri_decode_works() {
+        let mut decoder = Decoder::new(LogFormat::CRI);
+        assert_eq!(decoder, Decoder::CRI(Default::default()));
+
+        let line = decoder
+            .decode("2021-03-16T09:05:01.461813069Z stdout F hello world\n", 0)
+            .unwrap();
+        assert_eq!(line.message, "hello world");
+        assert_eq!(line.stream, "stdout");
+        assert_eq!(line.time, "2021-03-16T09:05:01.461813069Z");
+
+        // partial fragments are joined per stream, stderr is not mixed in
+        assert_eq!(
+            decoder.decode("2021-03-16T09:05:02.000000000Z stdout P aaa\n", 10),
+            None
+        );
+        let line = decoder
+            .decode("2021-03-16T09:05:02.100000000Z stderr F oops\n", 0)
+            .unwrap();
+        assert_eq!(line.message, "oops");
+        assert_eq!(
+            decoder.decode("2021-03-16T09:05:02.200000000Z stdout P bbb\n", 30),
+            None
+        );
+        let line = decoder
+            .decode("2021-03-16T09:05:02.300000000Z stdout F ccc\n", 40)
+            .unwrap();
+        assert_eq!(line.message, "aaabbbccc");
+        assert_eq!(line.offset, 40);
+        assert_eq!(line.fragments, vec![10, 30]);
+        assert_eq!(line.stream, "stdout");
+        assert_eq!(line.time, "2021-03-16T09:05:02.000000000Z");
+
+        // an empty message has no content field
+        let line = decoder
+            .decode("2021-03-16T09:05:03.000000000Z stdout F\n", 0)
+            .unwrap();
+        assert_eq!(line.message, "");
+    }
+
+    #[test]
+    fn cri_decode_keeps_broken_lines() {
+        let mut decoder = Decoder::new(LogFormat::CRI);
+        for line in &[
+            "the quick brown fox jumps\n",
+            "2021-03-16 stdout F hello\n",
+            "2021-03-16T09:05:01Z console F hello\n",
+            "2021-03-16T09:05:01Z stdout X hello\n",
+        ] {
+            let decoded = decoder.decode(line, 0).unwrap();
+            assert_eq!(decoded.message, *line);
+            assert_eq!(decoded.stream, "");
+        }
+        // a pending fragment is not touched by a broken line
+        assert_eq!(
+            decoder.decode("2021-03-16T09:05:02Z stdout P aaa\n", 0),
+            None
+        );
+        decoder.decode("not a cri line\n", 0).unwrap();
+        let line = decoder
+            .decode("2021-03-16T09:05:03Z stdout F bbb\n", 0)
+            .unwrap();
+        assert_eq!(line.message, "aaabbb");
+    }
+}
@@ -0,0 +1,112 @@
+use common::Ack;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Line {
+    Pending,
+    Acked,
+    // never taken by the output, nothing after it may be committed
+    Lost,
+}
+
+#[derive(Default)]
+struct Lines {
+    // end offset and state of the lines handed out, in file order
+    lines: VecDeque<(i64, Line)>,
+    stuck: bool,
+}
+
+// the lines of one file the outputs have not acknowledged yet. the offset
+// committed is the end of the acknowledged prefix, so a line acked early
+// never commits past a line before it that is still in flight
+pub(crate) struct Inflight {
+    lines: Mutex<Lines>,
+    commit: Box<dyn Fn(i64) + Send + Sync>,
+}
+
+impl Inflight {
+    pub(crate) fn new<F>(commit: F) -> Arc<Self>
+    where
+        F: Fn(i64) + Send + Sync + 'static,
+    {
+        Arc::new(Self {
+            lines: Mutex::new(Lines::default()),
+            commit: Box::new(commit),
+        })
+    }
+
+    // the ack of the line ending at `offset`, offsets are handed out increasing
+    pub(crate) fn track(self: &Arc<Self>, offset: i64) -> Ack {
+        match self.lines.lock() {
+            Ok(mut lines) if !lines.stuck => lines.lines.push_back((offset, Line::Pending)),
+            _ => return Ack::none(),
+        }
+        let inflight = self.clone();
+        Ack::new(move || inflight.ack(offset))
+    }
+
+    // the line ending at `offset` was dropped, the offset stays before it
+    // until the file is collected again
+    pub(crate) fn lose(&self, offset: i64) {
+        self.set(offset, Line::Lost);
+    }
+
+    // acks a line tracked without keeping its ack
+    pub(crate) fn ack(&self, offset: i64) {
+        self.set(offset, Line::Acked);
+    }
+
+    fn set(&self, offset: i64, state: Line) {
+        let mut lines = match self.lines.lock() {
+            Ok(lines) => lines,
+            Err(_) => return,
+        };
+        if let Ok(index) = lines.lines.binary_search_by_key(&offset, |(o, _)| *o) {
+            lines.lines[index].1 = state;
+        }
+        let mut committed = None;
+        while let Some((offset, Line::Acked)) = lines.lines.front() {
+            committed = Some(*offset);
+            lines.lines.pop_front();
+        }
+        if let Some((_, Line::Lost)) = lines.lines.front() {
+            lines.lines.clear();
+            lines.stuck = true;
+        }
+        // committed under the lock, the commits of a file stay in order
+        if let Some(offset) = committed {
+            (self.commit)(offset);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Inflight;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn commits_acknowledged_prefix() {
+        let commits = Arc::new(Mutex::new(vec![]));
+        let commits_clone = commits.clone();
+        let inflight = Inflight::new(move |offset| commits_clone.lock().unwrap().push(offset));
+
+        let (a, b, c) = (inflight.track(2), inflight.track(4), inflight.track(6));
+        c.ack();
+        b.ack();
+        assert!(commits.lock().unwrap().is_empty());
+        a.ack();
+        assert_eq!(*commits.lock().unwrap(), vec![6]);
+
+        // a dropped line holds the offset before it
+        let d = inflight.track(8);
+        inflight.track(10);
+        let f = inflight.track(12);
+        inflight.lose(10);
+        f.ack();
+        d.ack();
+        inflight.track(14).ack();
+        assert_eq!(*commits.lock().unwrap(), vec![6, 8]);
+    }
+}
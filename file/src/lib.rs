@@ -1,13 +1,17 @@
-#![feature(seek_stream_len)]
 extern crate crossbeam_channel;
 use async_std::task;
-use crossbeam_channel::{unbounded as async_channel, Sender};
+use crossbeam_channel::{unbounded as async_channel, RecvTimeoutError, Sender};
 use db::Pod;
-use output::OTS;
 use serde_json::json;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Seek, SeekFrom};
+
+mod decoder;
+mod inflight;
+mod multiline;
+mod tail;
+pub use decoder::{Decoder, LogLine};
+pub use multiline::Aggregator;
+use tail::Tail;
 
 pub enum SendFileEvent {
     Close,
@@ -66,95 +70,116 @@ impl FileReaderWriter {
     }
 
     fn open(&mut self, pod: &mut Pod) {
-        let mut offset = pod.offset;
-        let mut file = match File::open(&pod.path) {
-            Ok(file) => file,
+        let mut tail = match Tail::open(pod) {
+            Ok(tail) => tail,
             Err(e) => {
                 eprintln!("frw open file {:?} error: {:?}", pod.path, e);
                 return;
             }
         };
-        if let Err(e) = file.seek(SeekFrom::Current(offset)) {
-            eprintln!("frw open event seek failed, error: {}", e);
-            return;
-        }
-
-        let file_size = file.stream_len().unwrap();
-        let mut br = BufReader::new(file);
-        let mut bf = String::new();
-        let outputs = OTS.clone();
 
-        loop {
-            let cur_size = br.read_line(&mut bf).unwrap();
-            if let Ok(mut ot) = outputs.lock() {
-                ot.output(&pod.output, &encode_message(&pod, bf.as_str()))
-            }
-            db::incr_offset(&pod.path, cur_size as i64);
-            bf.clear();
-
-            offset += cur_size as i64;
-            if offset >= file_size as i64 {
-                break;
-            }
-        }
+        pod.offset = tail.offset;
+        pod.dev = tail.pod.dev;
+        pod.inode = tail.pod.inode;
+        pod.epoch = tail.pod.epoch;
+        // the handle is not registered yet, an update would dispatch this open again
+        db::track(pod.set_state_run());
+        tail.drain();
 
-        pod.offset = offset;
-        let thread_pod = pod.clone();
         let (tx, rx) = async_channel::<SendFileEvent>();
         task::spawn(async move {
-            while let Ok(evt) = rx.recv() {
-                match evt {
-                    SendFileEvent::Close => {
+            loop {
+                match rx.recv_timeout(tail.flush_interval()) {
+                    Ok(SendFileEvent::Close) => {
+                        tail.flush();
                         break;
                     }
-                    _ => {
-                        let incr_offset = br.read_line(&mut bf).unwrap();
-                        if let Ok(mut ot) = outputs.lock() {
-                            ot.output(
-                                &thread_pod.output,
-                                &encode_message(&thread_pod, bf.as_str()),
-                            )
+                    Ok(_) => {
+                        if tail.truncated() {
+                            if let Err(e) = tail.rewind() {
+                                eprintln!(
+                                    "frw rewind truncated {:?} error: {:?}",
+                                    tail.pod.path, e
+                                );
+                                break;
+                            }
+                        }
+                        tail.drain();
+                        if tail.rotated() {
+                            if let Err(e) = tail.reopen() {
+                                eprintln!("frw reopen rotated {:?} error: {:?}", tail.pod.path, e);
+                                break;
+                            }
+                            tail.drain();
                         }
-                        db::incr_offset(&thread_pod.path, incr_offset as i64);
-                        bf.clear();
                     }
+                    Err(RecvTimeoutError::Timeout) => tail.flush_expired(),
+                    Err(RecvTimeoutError::Disconnected) => break,
                 };
             }
         });
 
         self.file_handles.insert(pod.path.to_string(), tx);
-
-        db::update(&pod.set_state_run())
     }
 }
 
-fn encode_message<'a>(pod: &'a Pod, message: &'a str) -> String {
-    if message.len() == 0 {
+fn encode_message<'a>(pod: &'a Pod, line: &'a LogLine) -> String {
+    if line.message.is_empty() {
         return "".to_string();
     }
-    json!({
+    let mut message = json!({
         "custom":
             {
               "nodeId":pod.pod_name,
+              "ns":pod.ns,
+              "nodeName":pod.node_name,
               "container":pod.container,
               "serviceName":pod.service_name,
               "ips":pod.ips,
               "version":"v1.0.0",
             //   "path":pod.path.to_string(),
             },
-        "message":message}
-    )
-    .to_string()
+        "message":line.message}
+    );
+    // runtime metadata, only known for docker json-file and cri logs
+    if !line.stream.is_empty() {
+        message["stream"] = json!(line.stream);
+    }
+    if !line.time.is_empty() {
+        message["time"] = json!(line.time);
+    }
+    message.to_string()
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::FileReaderWriter;
+    use crate::{encode_message, FileReaderWriter, LogLine};
     use db::Pod;
+    use serde_json::Value;
 
     #[test]
     fn it_works() {
         let mut input = FileReaderWriter::new(10);
         input.open_event(&mut Pod::default());
     }
+
+    #[test]
+    fn encode_message_with_metadata() {
+        let line = LogLine {
+            message: "hello".to_string(),
+            stream: "stdout".to_string(),
+            time: "2021-03-16T09:05:01.461813069Z".to_string(),
+            ..Default::default()
+        };
+        let v = serde_json::from_str::<Value>(&encode_message(&Pod::default(), &line)).unwrap();
+        assert_eq!(v["message"], "hello");
+        assert_eq!(v["stream"], "stdout");
+        assert_eq!(v["time"], "2021-03-16T09:05:01.461813069Z");
+        assert_eq!(v["custom"]["ns"], "");
+
+        let v =
+            serde_json::from_str::<Value>(&encode_message(&Pod::default(), &LogLine::raw("hello")))
+                .unwrap();
+        assert_eq!(v.get("stream"), None);
+    }
 }
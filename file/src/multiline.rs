@@ -0,0 +1,171 @@
+use super::LogLine;
+use common::Result;
+use db::Multiline;
+use regex::Regex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_LINES: usize = 500;
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+pub struct Aggregator {
+    start: Option<Regex>,
+    continuation: Option<Regex>,
+    max_lines: usize,
+    timeout: Duration,
+    pending: Option<LogLine>,
+    lines: usize,
+    updated: Instant,
+}
+
+impl Aggregator {
+    pub fn new(cfg: &Multiline) -> Result<Self> {
+        if cfg.start.is_empty() && cfg.continuation.is_empty() {
+            return Err("multiline needs a start or continuation rule".into());
+        }
+        let start = if cfg.start.is_empty() {
+            None
+        } else {
+            Some(Regex::new(&cfg.start)?)
+        };
+        let continuation = if cfg.continuation.is_empty() {
+            None
+        } else {
+            Some(Regex::new(&cfg.continuation)?)
+        };
+        let max_lines = match cfg.max_lines {
+            0 => DEFAULT_MAX_LINES,
+            n => n,
+        };
+        let timeout_ms = match cfg.timeout_ms {
+            0 => DEFAULT_TIMEOUT_MS,
+            n => n,
+        };
+        Ok(Self {
+            start,
+            continuation,
+            max_lines,
+            timeout: Duration::from_millis(timeout_ms),
+            pending: None,
+            lines: 0,
+            updated: Instant::now(),
+        })
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn is_continuation(&self, message: &str) -> bool {
+        if let Some(continuation) = &self.continuation {
+            if continuation.is_match(message) {
+                return true;
+            }
+        }
+        match &self.start {
+            Some(start) => !start.is_match(message),
+            None => false,
+        }
+    }
+
+    // returns the previous record once `line` starts a new one
+    pub fn push(&mut self, line: LogLine) -> Option<LogLine> {
+        self.updated = Instant::now();
+        if self.lines < self.max_lines && self.is_continuation(&line.message) {
+            if let Some(pending) = self.pending.as_mut() {
+                if !pending.message.ends_with('\n') {
+                    pending.message.push('\n');
+                }
+                pending.message.push_str(&line.message);
+                pending.offset = line.offset;
+                pending.fragments.extend(line.fragments);
+                self.lines += 1;
+                return None;
+            }
+        }
+        self.lines = 1;
+        self.pending.replace(line)
+    }
+
+    pub fn flush(&mut self) -> Option<LogLine> {
+        self.lines = 0;
+        self.pending.take()
+    }
+
+    // flush a record nothing has been appended to within the timeout
+    pub fn flush_expired(&mut self) -> Option<LogLine> {
+        if self.updated.elapsed() < self.timeout {
+            return None;
+        }
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(agg: &mut Aggregator, lines: &[&str]) -> Vec<String> {
+        let mut res = lines
+            .iter()
+            .filter_map(|line| agg.push(LogLine::raw(line)))
+            .map(|line| line.message)
+            .collect::<Vec<String>>();
+        if let Some(line) = agg.flush() {
+            res.push(line.message);
+        }
+        res
+    }
+
+    #[test]
+    fn continuation_rule_works() {
+        let mut agg = Aggregator::new(&Multiline {
+            continuation: r#"^(\s|Caused by:)"#.to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let res = messages(
+            &mut agg,
+            &[
+                "Exception in thread \"main\" java.lang.IllegalStateException\n",
+                "\tat com.example.Main.main(Main.java:10)\n",
+                "Caused by: java.lang.NullPointerException\n",
+                "\t... 1 more\n",
+                "next line\n",
+            ],
+        );
+        assert_eq!(res.len(), 2);
+        assert!(res[0].starts_with("Exception"));
+        assert!(res[0].ends_with("\t... 1 more\n"));
+        assert_eq!(res[1], "next line\n");
+    }
+
+    #[test]
+    fn start_rule_and_max_lines_works() {
+        let mut agg = Aggregator::new(&Multiline {
+            start: r#"^\d{4}-\d{2}-\d{2}"#.to_string(),
+            max_lines: 2,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let res = messages(
+            &mut agg,
+            &["2021-03-16 error", "Traceback", "  File x", "2021-03-16 ok"],
+        );
+        assert_eq!(
+            res,
+            vec!["2021-03-16 error\nTraceback", "  File x", "2021-03-16 ok"]
+        );
+    }
+
+    #[test]
+    fn invalid_config() {
+        assert!(Aggregator::new(&Multiline::default()).is_err());
+        assert!(Aggregator::new(&Multiline {
+            start: "(".to_string(),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,553 @@
+use super::inflight::Inflight;
+use super::{encode_message, Aggregator, Decoder, LogLine};
+use common::Ack;
+use db::Pod;
+use output::{fan_out, QueueFull, OTS};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::sync::{Arc, Mutex};
+use std::{thread, time::Duration};
+
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_BACKPRESSURE: Duration = Duration::from_secs(1);
+
+// reads lines of one file and emits them through decoder and multiline aggregator
+pub(crate) struct Tail {
+    pub(crate) pod: Pod,
+    pub(crate) offset: i64,
+    br: BufReader<File>,
+    bf: Vec<u8>,
+    decoder: Decoder,
+    multiline: Option<Aggregator>,
+    // one per channel, in the order of `pod.channels()`
+    inflight: Vec<Arc<Inflight>>,
+}
+
+// tracks the acks of every output of the file (dev, inode, epoch) the tail reads,
+// each output keeps the offset it acknowledged and the lowest one is committed
+fn inflight(pod: &Pod) -> Vec<Arc<Inflight>> {
+    let commit = Pod {
+        path: pod.path.clone(),
+        dev: pod.dev,
+        inode: pod.inode,
+        epoch: pod.epoch,
+        ..Default::default()
+    };
+    let channels = pod.channels().len();
+    let acked = Arc::new(Mutex::new(vec![pod.offset; channels]));
+    (0..channels)
+        .map(|i| {
+            let (commit, acked) = (commit.clone(), acked.clone());
+            Inflight::new(move |offset| {
+                let mut acked = match acked.lock() {
+                    Ok(acked) => acked,
+                    Err(_) => return,
+                };
+                acked[i] = offset;
+                if let Some(offset) = acked.iter().min() {
+                    db::commit_offset(&Pod {
+                        offset: *offset,
+                        ..commit.clone()
+                    })
+                }
+            })
+        })
+        .collect()
+}
+
+impl Tail {
+    pub(crate) fn open(pod: &Pod) -> io::Result<Self> {
+        let mut pod = pod.clone();
+        let mut file = File::open(&pod.path)?;
+        let md = file.metadata()?;
+        let rotated = pod.inode != 0 && (pod.dev, pod.inode) != (md.dev(), md.ino());
+        pod.dev = md.dev();
+        pod.inode = md.ino();
+        // the stored offset belongs to another file once the path was rotated
+        if rotated {
+            pod.offset = 0;
+            db::rotate(&pod);
+        } else if pod.offset > md.len() as i64 {
+            pod.epoch = db::truncate(&pod);
+            pod.offset = 0;
+        }
+        file.seek(SeekFrom::Start(pod.offset as u64))?;
+
+        let multiline = match &pod.multiline {
+            Some(cfg) => match Aggregator::new(cfg) {
+                Ok(agg) => Some(agg),
+                Err(e) => {
+                    eprintln!("frw multiline config of {:?} error: {:?}", pod.path, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        Ok(Self {
+            offset: pod.offset,
+            br: BufReader::new(file),
+            bf: Vec::new(),
+            decoder: Decoder::new(pod.format),
+            multiline,
+            inflight: inflight(&pod),
+            pod,
+        })
+    }
+
+    // read one complete line, returns the bytes consumed or 0 at EOF,
+    // an incomplete line stays buffered until the writer finishes it
+    fn read_line(&mut self) -> usize {
+        if let Err(e) = self.br.read_until(b'\n', &mut self.bf) {
+            eprintln!("frw read {:?} error: {:?}", self.pod.path, e);
+            return 0;
+        }
+        if !self.bf.ends_with(b"\n") {
+            return 0;
+        }
+        self.consume()
+    }
+
+    fn consume(&mut self) -> usize {
+        let size = self.bf.len();
+        self.offset += size as i64;
+        let line = String::from_utf8_lossy(&self.bf).to_string();
+        self.bf.clear();
+        let line = match self.decoder.decode(&line, self.offset) {
+            Some(line) => line,
+            None => {
+                // a cri partial line held by the decoder keeps the offset before
+                // it, its record acks it once delivered
+                for inflight in &self.inflight {
+                    inflight.track(self.offset);
+                }
+                return size;
+            }
+        };
+        let line = match self.multiline.as_mut() {
+            Some(agg) => agg.push(line),
+            None => Some(line),
+        };
+        if let Some(line) = line {
+            self.emit(&line);
+        }
+        size
+    }
+
+    pub(crate) fn drain(&mut self) {
+        while self.read_line() > 0 {}
+    }
+
+    // the path now points to another file than the one being read
+    pub(crate) fn rotated(&self) -> bool {
+        match fs::metadata(&self.pod.path) {
+            Ok(md) => (md.dev(), md.ino()) != (self.pod.dev, self.pod.inode),
+            // renamed away and not recreated yet, keep reading the old file
+            Err(_) => false,
+        }
+    }
+
+    // finish the rotated file and continue with the new one from offset 0
+    pub(crate) fn reopen(&mut self) -> io::Result<()> {
+        self.drain();
+        if !self.bf.is_empty() {
+            self.consume();
+        }
+        self.flush();
+
+        let file = File::open(&self.pod.path)?;
+        let md = file.metadata()?;
+        self.pod.dev = md.dev();
+        self.pod.inode = md.ino();
+        self.pod.offset = 0;
+        self.offset = 0;
+        self.br = BufReader::new(file);
+        self.decoder = Decoder::new(self.pod.format);
+        self.inflight = inflight(&self.pod);
+        db::rotate(&self.pod);
+        Ok(())
+    }
+
+    // copytruncate or an in place shrink leaves the offset past the end of file
+    pub(crate) fn truncated(&self) -> bool {
+        match self.br.get_ref().metadata() {
+            Ok(md) => (md.len() as i64) < self.offset + self.bf.len() as i64,
+            Err(_) => false,
+        }
+    }
+
+    pub(crate) fn rewind(&mut self) -> io::Result<()> {
+        self.br.seek(SeekFrom::Start(0))?;
+        self.bf.clear();
+        self.flush();
+        self.pod.offset = self.offset;
+        self.pod.epoch = db::truncate(&self.pod);
+        self.pod.offset = 0;
+        self.offset = 0;
+        self.decoder = Decoder::new(self.pod.format);
+        self.inflight = inflight(&self.pod);
+        Ok(())
+    }
+
+    pub(crate) fn flush_interval(&self) -> Duration {
+        match &self.multiline {
+            Some(agg) => agg.timeout(),
+            None => IDLE_INTERVAL,
+        }
+    }
+
+    pub(crate) fn flush_expired(&mut self) {
+        if let Some(line) = self.multiline.as_mut().and_then(|agg| agg.flush_expired()) {
+            self.emit(&line);
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        if let Some(line) = self.multiline.as_mut().and_then(|agg| agg.flush()) {
+            self.emit(&line);
+        }
+    }
+
+    // the offset is committed only once every output acknowledged the record
+    // and every record before it. a full output slows this file down, the line
+    // is retried on the outputs that did not take it yet
+    fn emit(&self, line: &LogLine) {
+        let message = encode_message(&self.pod, line);
+        let channels = self.pod.channels();
+        let mut pending = channels
+            .iter()
+            .zip(self.inflight.iter())
+            .map(|(channel, inflight)| (*channel, inflight, track(inflight, line)))
+            .collect::<Vec<_>>();
+        let mut wait = Duration::from_millis(1);
+        loop {
+            pending.retain(|(channel, inflight, ack)| {
+                // a single output is written directly, more go through their own lanes
+                let res = match channels.len() {
+                    1 => match OTS.lock() {
+                        Ok(mut ot) => ot.output_with_ack(channel, &message, ack.clone()),
+                        Err(e) => Err(format!("{:?}", e).into()),
+                    },
+                    _ => fan_out(channel, &message, ack.clone()),
+                };
+                match res {
+                    Err(e) if e.is::<QueueFull>() => true,
+                    Err(e) => {
+                        eprintln!("frw output {:?} error: {:?}", channel, e);
+                        inflight.lose(line.offset);
+                        false
+                    }
+                    Ok(_) => false,
+                }
+            });
+            if pending.is_empty() {
+                return;
+            }
+            // the lock is released while waiting
+            thread::sleep(wait);
+            wait = (wait * 2).min(MAX_BACKPRESSURE);
+        }
+    }
+}
+
+// the ack of a record also acks the partial lines joined into it
+fn track(inflight: &Arc<Inflight>, line: &LogLine) -> Ack {
+    let ack = inflight.track(line.offset);
+    if line.fragments.is_empty() {
+        return ack;
+    }
+    let (inflight, fragments) = (inflight.clone(), line.fragments.clone());
+    Ack::new(move || {
+        for offset in &fragments {
+            inflight.ack(*offset);
+        }
+        ack.ack();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tail;
+    use common::{Ack, Item, Result};
+    use db::{LogFormat, Pod};
+    use event::Listener;
+    use output::{IOutput, Output, OTS};
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::{thread, time::Duration};
+
+    // holds every record until the test releases it
+    struct Gate(Arc<Mutex<Vec<Ack>>>);
+
+    impl IOutput for Gate {
+        fn write(&mut self, _: &str, _: Item) -> Result<()> {
+            Ok(())
+        }
+
+        fn write_with_ack(&mut self, _: &str, _: Item, ack: Ack) -> Result<()> {
+            self.0.lock().unwrap().push(ack);
+            Ok(())
+        }
+    }
+
+    struct Truncated(Mutex<mpsc::Sender<Pod>>);
+
+    impl Listener<Pod> for Truncated {
+        fn handle(&self, pod: Pod) {
+            let _ = self.0.lock().unwrap().send(pod);
+        }
+    }
+
+    fn committed(path: &str, offset: i64) -> i64 {
+        let mut committed = 0;
+        for _ in 0..100 {
+            committed = db::get(path).map(|pod| pod.offset).unwrap_or(0);
+            if committed == offset {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        committed
+    }
+
+    #[test]
+    fn rotate_works() {
+        let dir = std::env::temp_dir().join("harvest_file_tail_rotate");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("0.log");
+        fs::write(&path, "a\nb\nincomple").unwrap();
+
+        let mut tail = Tail::open(&Pod {
+            path: path.to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        tail.drain();
+        // the incomplete line is held back
+        assert_eq!(tail.offset, 4);
+        assert!(!tail.rotated());
+
+        fs::rename(&path, dir.join("0.log.1")).unwrap();
+        assert!(!tail.rotated());
+        fs::write(&path, "c\n").unwrap();
+        assert!(tail.rotated());
+
+        let mut old = OpenOptions::new()
+            .append(true)
+            .open(dir.join("0.log.1"))
+            .unwrap();
+        old.write_all(b"te\n").unwrap();
+
+        tail.reopen().unwrap();
+        assert_eq!(tail.offset, 0);
+        assert!(!tail.rotated());
+        tail.drain();
+        assert_eq!(tail.offset, 2);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn truncate_works() {
+        let dir = std::env::temp_dir().join("harvest_file_tail_truncate");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        fs::write(&path, "aaaa\nbbbb\n").unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        db::registry_truncate_event_listener(Truncated(Mutex::new(tx)));
+        let path = path.to_str().unwrap().to_string();
+        let truncated = || loop {
+            let pod = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            if pod.path == path {
+                return pod;
+            }
+        };
+
+        // offset restored past the end of file
+        let pod = Pod {
+            path: path.clone(),
+            offset: 100,
+            ..Default::default()
+        };
+        db::insert(&pod);
+        let mut tail = Tail::open(&pod).unwrap();
+        assert_eq!(tail.offset, 0);
+        assert_eq!((truncated().offset, tail.pod.epoch), (100, 1));
+        tail.drain();
+        assert_eq!(tail.offset, 10);
+        assert!(!tail.truncated());
+
+        // copytruncate
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+        fs::write(&path, "c\n").unwrap();
+        assert!(tail.truncated());
+        tail.rewind().unwrap();
+        let pod = truncated();
+        assert_eq!((pod.offset, pod.epoch), (10, 2));
+        tail.drain();
+        assert_eq!(tail.offset, 2);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn commit_after_ack() {
+        let dir = std::env::temp_dir().join("harvest_file_tail_commit");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log").to_str().unwrap().to_string();
+        fs::write(&path, "a\nb\n").unwrap();
+
+        let pod = Pod {
+            path: path.clone(),
+            output: "fake_output".to_string(),
+            ..Default::default()
+        };
+        let mut tail = Tail::open(&pod).unwrap();
+        db::insert(&tail.pod);
+        tail.drain();
+
+        // fake_output acks synchronously
+        assert_eq!(committed(&path, 4), 4);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn commit_after_fan_out() {
+        let dir = std::env::temp_dir().join("harvest_file_tail_fan_out");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log").to_str().unwrap().to_string();
+        fs::write(&path, "a\nb\n").unwrap();
+
+        let held = Arc::new(Mutex::new(vec![]));
+        if let Ok(mut ot) = OTS.lock() {
+            ot.registry_output("tail_fan_out_gate", Output::new(Gate(held.clone())));
+        }
+        let pod = Pod {
+            path: path.clone(),
+            output: "fake_output".to_string(),
+            outputs: vec!["tail_fan_out_gate".to_string(), "fake_output".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(pod.channels(), vec!["fake_output", "tail_fan_out_gate"]);
+        let mut tail = Tail::open(&pod).unwrap();
+        db::insert(&tail.pod);
+        tail.drain();
+
+        // fake_output acked both lines, the lowest offset is the gate's
+        assert_eq!(committed(&path, 4), 0);
+        for _ in 0..100 {
+            if held.lock().unwrap().len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let acks = held.lock().unwrap().drain(..).collect::<Vec<Ack>>();
+        acks[0].ack();
+        assert_eq!(committed(&path, 2), 2);
+        acks[1].ack();
+        assert_eq!(committed(&path, 4), 4);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn commit_acknowledged_prefix() {
+        let dir = std::env::temp_dir().join("harvest_file_tail_prefix");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log").to_str().unwrap().to_string();
+        fs::write(&path, "aaaa\nbbbb\n").unwrap();
+
+        let held = Arc::new(Mutex::new(vec![]));
+        if let Ok(mut ot) = OTS.lock() {
+            ot.registry_output("tail_prefix_gate", Output::new(Gate(held.clone())));
+        }
+        let pod = Pod {
+            path: path.clone(),
+            output: "tail_prefix_gate".to_string(),
+            ..Default::default()
+        };
+        let mut tail = Tail::open(&pod).unwrap();
+        db::insert(&tail.pod);
+        tail.drain();
+
+        // the second line acked first commits nothing
+        let acks = held.lock().unwrap().drain(..).collect::<Vec<Ack>>();
+        assert_eq!(acks.len(), 2);
+        acks[1].ack();
+        assert_eq!(committed(&path, 10), 0);
+
+        // copytruncate, the first ack belongs to the old content
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+        fs::write(&path, "c\n").unwrap();
+        assert!(tail.truncated());
+        tail.rewind().unwrap();
+        tail.drain();
+        acks[0].ack();
+        assert_eq!(committed(&path, 10), 0);
+
+        for ack in held.lock().unwrap().drain(..) {
+            ack.ack();
+        }
+        assert_eq!(committed(&path, 2), 2);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn commit_after_cri_fragment() {
+        let dir = std::env::temp_dir().join("harvest_file_tail_cri");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("0.log").to_str().unwrap().to_string();
+        let lines = [
+            "2021-03-16T09:05:01Z stdout P aaa\n",
+            "2021-03-16T09:05:02Z stderr F oops\n",
+            "2021-03-16T09:05:03Z stdout F bbb\n",
+        ];
+        fs::write(&path, lines.concat()).unwrap();
+
+        let held = Arc::new(Mutex::new(vec![]));
+        if let Ok(mut ot) = OTS.lock() {
+            ot.registry_output("tail_cri_gate", Output::new(Gate(held.clone())));
+        }
+        let pod = Pod {
+            path: path.clone(),
+            output: "tail_cri_gate".to_string(),
+            format: LogFormat::CRI,
+            ..Default::default()
+        };
+        let mut tail = Tail::open(&pod).unwrap();
+        db::insert(&tail.pod);
+        tail.drain();
+
+        // the stderr record is delivered first, the stdout fragment before it is not
+        let acks = held.lock().unwrap().drain(..).collect::<Vec<Ack>>();
+        assert_eq!(acks.len(), 2);
+        acks[0].ack();
+        assert_eq!(committed(&path, lines[0].len() as i64), 0);
+
+        let size = lines.concat().len() as i64;
+        acks[1].ack();
+        assert_eq!(committed(&path, size), size);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
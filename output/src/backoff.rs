@@ -0,0 +1,56 @@
+use std::time::{Duration, Instant};
+
+const MIN_BACKOFF_MS: u64 = 100;
+const MAX_BACKOFF_MS: u64 = 10_000;
+
+// exponential delay between attempts against an unavailable sink
+pub(crate) struct Backoff {
+    delay: Duration,
+    next: Instant,
+}
+
+impl Backoff {
+    pub(crate) fn new() -> Self {
+        Self {
+            delay: Duration::from_millis(MIN_BACKOFF_MS),
+            next: Instant::now(),
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.delay = Duration::from_millis(MIN_BACKOFF_MS);
+        self.next = Instant::now();
+    }
+
+    // the delay before the next attempt, doubled for the one after
+    pub(crate) fn fail(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (delay * 2).min(Duration::from_millis(MAX_BACKOFF_MS));
+        self.next = Instant::now() + delay;
+        delay
+    }
+
+    pub(crate) fn ready(&self) -> bool {
+        Instant::now() >= self.next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Backoff;
+
+    #[test]
+    fn backoff_works() {
+        let mut backoff = Backoff::new();
+        assert!(backoff.ready());
+        let delays = (0..10)
+            .map(|_| backoff.fail().as_millis())
+            .collect::<Vec<u128>>();
+        assert_eq!(&delays[..4], &[100, 200, 400, 800]);
+        assert_eq!(delays[9], 10_000);
+        assert!(!backoff.ready());
+        backoff.reset();
+        assert!(backoff.ready());
+        assert_eq!(backoff.fail().as_millis(), 100);
+    }
+}
@@ -0,0 +1,316 @@
+use super::backoff::Backoff;
+use super::{Ack, Result, Uri};
+use flate2::{write::GzEncoder, Compression};
+use ringbuf::Consumer as RConsumer;
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_BATCH_BYTES: usize = 1024 * 1024;
+const DEFAULT_LINGER_MS: u64 = 1000;
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+// header.<name>=<value> in the channel query adds a request header
+pub(crate) const HEADER_PREFIX: &str = "header.";
+
+// scheme of an http based sink, tls=true asks for https
+pub(crate) fn proto(uri: &Uri) -> Result<&'static str> {
+    match uri.param_or("tls", false)? {
+        true => Ok("https"),
+        false => Ok("http"),
+    }
+}
+
+// query parameters named `<prefix><name>`, e.g. header.X-Token=abc, sorted by name
+pub(crate) fn prefixed(uri: &Uri, prefix: &str) -> Result<Vec<(String, String)>> {
+    let mut params = uri
+        .query
+        .iter()
+        .filter_map(|(k, v)| {
+            k.strip_prefix(prefix)
+                .map(|name| (name.to_string(), v.to_string()))
+        })
+        .collect::<Vec<(String, String)>>();
+    params.sort();
+    if params.iter().any(|(name, _)| name.is_empty()) {
+        return Err(format!("output parameter `{}` needs a name", prefix).into());
+    }
+    Ok(params)
+}
+
+pub(crate) fn gzip(body: &[u8]) -> Result<Vec<u8>> {
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(body)?;
+    Ok(gz.finish()?)
+}
+
+// batch limits and request timeout of a sink,
+// batch, batch_bytes, linger_ms and timeout_ms in the channel query
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Batching {
+    pub(crate) batch: usize,
+    pub(crate) batch_bytes: usize,
+    pub(crate) linger: Duration,
+    pub(crate) timeout: Duration,
+}
+
+impl Batching {
+    pub(crate) fn from_uri(uri: &Uri, default_batch: usize) -> Result<Self> {
+        let (batch, batch_bytes) = (
+            uri.param_or("batch", default_batch)?,
+            uri.param_or("batch_bytes", DEFAULT_BATCH_BYTES)?,
+        );
+        if batch == 0 || batch_bytes == 0 {
+            return Err("output batch and batch_bytes must be greater than 0".into());
+        }
+        Ok(Self {
+            batch,
+            batch_bytes,
+            linger: Duration::from_millis(uri.param_or("linger_ms", DEFAULT_LINGER_MS)?),
+            timeout: Duration::from_millis(uri.param_or("timeout_ms", DEFAULT_TIMEOUT_MS)?),
+        })
+    }
+
+    // hands the queue to `flush` in batches, a record that would overflow
+    // batch_bytes starts the next batch and a batch older than linger goes out as it is
+    pub(crate) fn write_out<T, S, F>(&self, cons: &mut RConsumer<T>, size: S, mut flush: F)
+    where
+        S: Fn(&T) -> usize,
+        F: FnMut(Vec<T>),
+    {
+        let mut now = Instant::now();
+        let mut batch = Vec::with_capacity(self.batch);
+        let mut bytes = 0;
+        loop {
+            match cons.pop() {
+                Some(record) => {
+                    if !batch.is_empty() && bytes + size(&record) > self.batch_bytes {
+                        flush(std::mem::take(&mut batch));
+                        bytes = 0;
+                    }
+                    if batch.is_empty() {
+                        now = Instant::now();
+                    }
+                    bytes += size(&record);
+                    batch.push(record);
+                }
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+            if batch.len() >= self.batch || (!batch.is_empty() && now.elapsed() >= self.linger) {
+                flush(std::mem::take(&mut batch));
+                bytes = 0;
+            }
+        }
+    }
+}
+
+// an http endpoint taking encoded batches of records
+pub(crate) trait Sink: Send + 'static {
+    type Record: Send;
+
+    // names the sink in logs
+    const NAME: &'static str;
+
+    fn url(&self) -> &str;
+
+    // bytes a record adds to its batch
+    fn size(record: &Self::Record) -> usize;
+
+    fn encode(&self, records: &[Self::Record]) -> Result<Vec<u8>>;
+
+    // the post of a batch with its content type and headers
+    fn request(&self, agent: &ureq::Agent) -> ureq::Request;
+
+    // answers after which the same batch is sent again
+    fn retryable(&self, code: u16) -> bool {
+        code == 429 || code >= 500
+    }
+}
+
+// posts batches, waiting out retryable answers before sending the batch again.
+// any other answer refuses the batch for good and it is dropped, the acks of
+// a batch fire once it was taken or dropped
+pub(crate) struct Worker<S: Sink> {
+    sink: S,
+    batching: Batching,
+    agent: ureq::Agent,
+    backoff: Backoff,
+}
+
+impl<S: Sink> Worker<S> {
+    pub(crate) fn new(sink: S, batching: &Batching) -> Self {
+        Self {
+            sink,
+            agent: ureq::AgentBuilder::new().timeout(batching.timeout).build(),
+            batching: batching.clone(),
+            backoff: Backoff::new(),
+        }
+    }
+
+    pub(crate) fn write_out(&mut self, cons: &mut RConsumer<(S::Record, Ack)>) {
+        let batching = self.batching.clone();
+        batching.write_out(
+            cons,
+            |(record, _)| S::size(record),
+            |batch| self.flush(batch),
+        );
+    }
+
+    pub(crate) fn flush(&mut self, batch: Vec<(S::Record, Ack)>) {
+        let (records, acks): (Vec<S::Record>, Vec<Ack>) = batch.into_iter().unzip();
+        match self.sink.encode(&records) {
+            Ok(body) => self.post(&body, records.len()),
+            // encoding again would fail the same way
+            Err(e) => eprintln!(
+                "{} {:?} encode error, {} records dropped: {:?}",
+                S::NAME,
+                self.sink.url(),
+                records.len(),
+                e
+            ),
+        }
+        for ack in acks {
+            ack.ack();
+        }
+    }
+
+    fn post(&mut self, body: &[u8], records: usize) {
+        loop {
+            let wait = match self.sink.request(&self.agent).send_bytes(body) {
+                Ok(_) => {
+                    self.backoff.reset();
+                    return;
+                }
+                Err(ureq::Error::Status(code, res)) if self.sink.retryable(code) => {
+                    eprintln!(
+                        "{} {:?} status {}, retrying",
+                        S::NAME,
+                        self.sink.url(),
+                        code
+                    );
+                    let delay = self.backoff.fail();
+                    res.header("Retry-After")
+                        .and_then(|v| v.trim().parse::<u64>().ok())
+                        .map(Duration::from_secs)
+                        .unwrap_or(delay)
+                }
+                Err(ureq::Error::Status(code, res)) => {
+                    eprintln!(
+                        "{} {:?} rejected {} records, status {}: {}",
+                        S::NAME,
+                        self.sink.url(),
+                        records,
+                        code,
+                        res.into_string().unwrap_or_default()
+                    );
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("{} {:?} error: {}", S::NAME, self.sink.url(), e);
+                    self.backoff.fail()
+                }
+            };
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{prefixed, proto, Batching, Sink, Worker, HEADER_PREFIX};
+    use crate::{stand_in, Result, Uri};
+    use common::Ack;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    struct Lines(String);
+
+    impl Sink for Lines {
+        type Record = String;
+
+        const NAME: &'static str = "lines output";
+
+        fn url(&self) -> &str {
+            &self.0
+        }
+
+        fn size(record: &String) -> usize {
+            record.len()
+        }
+
+        fn encode(&self, records: &[String]) -> Result<Vec<u8>> {
+            if records.iter().any(|r| r.is_empty()) {
+                return Err("empty record".into());
+            }
+            Ok(records.join("\n").into_bytes())
+        }
+
+        fn request(&self, agent: &ureq::Agent) -> ureq::Request {
+            agent.post(&self.0)
+        }
+    }
+
+    #[test]
+    fn batching_from_uri() {
+        let uri =
+            Uri::parse("http://collector?tls=true&header.X-Token=abc&header.A=b&batch=2").unwrap();
+        assert_eq!(proto(&uri).unwrap(), "https");
+        assert_eq!(
+            prefixed(&uri, HEADER_PREFIX).unwrap(),
+            vec![
+                ("A".to_string(), "b".to_string()),
+                ("X-Token".to_string(), "abc".to_string())
+            ]
+        );
+        let batching = Batching::from_uri(&uri, 500).unwrap();
+        assert_eq!((batching.batch, batching.batch_bytes), (2, 1024 * 1024));
+        assert_eq!(batching.linger, Duration::from_secs(1));
+
+        for channel in &[
+            "http://collector?tls=yes",
+            "http://collector?batch=0",
+            "http://collector?batch_bytes=0",
+            "http://collector?linger_ms=-1",
+        ] {
+            let uri = Uri::parse(channel).unwrap();
+            assert!(proto(&uri).is_err() || Batching::from_uri(&uri, 500).is_err());
+        }
+        assert!(prefixed(
+            &Uri::parse("http://collector?header.=abc").unwrap(),
+            "header."
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn worker_retries_after() {
+        let (port, rx) = stand_in::serve(vec![
+            (503, "Retry-After: 0\r\n", ""),
+            (200, "", ""),
+            (400, "", "bad"),
+        ]);
+        let url = format!("http://127.0.0.1:{}/ingest", port);
+        let batching = Batching::from_uri(&Uri::parse(&url).unwrap(), 500).unwrap();
+        let mut worker = Worker::new(Lines(url), &batching);
+
+        let acked = Arc::new(AtomicUsize::new(0));
+        let ack = |acked: &Arc<AtomicUsize>| {
+            let acked = acked.clone();
+            Ack::new(move || {
+                acked.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        // taken after a retry, then refused for good, then never encoded
+        worker.flush(vec![
+            ("a".to_string(), ack(&acked)),
+            ("b".to_string(), ack(&acked)),
+        ]);
+        worker.flush(vec![("c".to_string(), ack(&acked))]);
+        assert_eq!(acked.load(Ordering::SeqCst), 3);
+        worker.flush(vec![(String::new(), ack(&acked))]);
+        assert_eq!(acked.load(Ordering::SeqCst), 4);
+
+        let bodies = rx.iter().map(|req| req.text()).collect::<Vec<String>>();
+        assert_eq!(bodies, vec!["a\nb", "a\nb", "c"]);
+    }
+}
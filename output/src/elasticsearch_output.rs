@@ -0,0 +1,433 @@
+use super::backoff::Backoff;
+use super::batch::{proto, Batching};
+use super::{Ack, Envelope, IOutput, Item, Output, QueueFull, Result, Uri};
+use chrono::{SecondsFormat, Utc};
+use ringbuf::{Consumer as RConsumer, Producer as RProducer, RingBuffer};
+use serde_json::{json, Value};
+use std::{fs, thread};
+
+const RING_SIZE: usize = 10240;
+const DEFAULT_BATCH: usize = 500;
+
+pub(crate) fn factory(uri: &Uri) -> Result<Box<dyn IOutput>> {
+    let cfg = ElasticsearchConfig::from_uri(uri)?;
+    Ok(Box::new(Output::new(ElasticsearchOutput::new(cfg))))
+}
+
+// index names are lowercase and must not hold \ / * ? " < > | , # : or spaces
+fn render_index(template: &str, envelope: &Envelope) -> Result<String> {
+    let template = template.replace("{date}", &Utc::now().format("%Y.%m.%d").to_string());
+    envelope.render(&template, |c| match c {
+        'a'..='z' | '0'..='9' | '.' | '_' | '-' | '+' => c,
+        'A'..='Z' => c.to_ascii_lowercase(),
+        _ => '_',
+    })
+}
+
+#[derive(Clone, Debug)]
+struct ElasticsearchConfig {
+    urls: Vec<String>,
+    index: String,
+    auth: Option<String>,
+    batching: Batching,
+}
+
+impl ElasticsearchConfig {
+    // channel = es://10.200.100.200:9200,10.200.100.201:9200/logs-{ns}-{date}?user=elastic&password_file=/etc/harvest/es
+    // index fields: {date} (yyyy.mm.dd, utc), {ns}, {pod}, {container}, {service_name}, {node}
+    // tls=true, batch, batch_bytes, linger_ms, timeout_ms
+    fn from_uri(uri: &Uri) -> Result<Self> {
+        let (hosts, index) = match uri.target.find('/') {
+            Some(i) => (&uri.target[..i], &uri.target[i + 1..]),
+            None => (uri.target.as_str(), ""),
+        };
+        let proto = proto(uri)?;
+        let urls = hosts
+            .split(',')
+            .filter(|h| !h.is_empty())
+            .map(|h| format!("{}://{}", proto, h))
+            .collect::<Vec<String>>();
+        if urls.is_empty() || index.is_empty() {
+            return Err(
+                format!("elasticsearch output `{}` expects hosts/index", uri.target).into(),
+            );
+        }
+        render_index(index, &Envelope::new(&Item::Default(String::new())))?;
+
+        let password = match (uri.param("password"), uri.param("password_file")) {
+            (_, Some(path)) => match fs::read_to_string(path) {
+                Ok(password) => Some(password.trim_end().to_string()),
+                Err(e) => {
+                    return Err(
+                        format!("elasticsearch password_file {:?} error: {}", path, e).into(),
+                    )
+                }
+            },
+            (password, None) => password.map(|p| p.to_string()),
+        };
+        let auth = match (uri.param("user"), password) {
+            (Some(user), password) => Some(format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", user, password.unwrap_or_default()))
+            )),
+            (None, Some(_)) => return Err("elasticsearch output password without user".into()),
+            (None, None) => None,
+        };
+
+        Ok(Self {
+            urls,
+            index: index.to_string(),
+            auth,
+            batching: Batching::from_uri(uri, DEFAULT_BATCH)?,
+        })
+    }
+}
+
+struct BulkItem {
+    index: String,
+    doc: String,
+    ack: Ack,
+}
+
+impl BulkItem {
+    fn new(index: String, item: &Item, ack: Ack) -> Self {
+        let mut doc = match item {
+            Item::JSON(value) if value.is_object() => value.clone(),
+            _ => json!({ "message": item.string() }),
+        };
+        // runtime time when the decoder found one, otherwise collection time
+        let timestamp = match Envelope::new(item).time() {
+            "" => Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            time => time.to_string(),
+        };
+        doc["@timestamp"] = json!(timestamp);
+        Self {
+            index,
+            doc: doc.to_string(),
+            ack,
+        }
+    }
+}
+
+pub(crate) struct ElasticsearchOutput {
+    cfg: ElasticsearchConfig,
+    queue: RProducer<BulkItem>,
+}
+
+impl ElasticsearchOutput {
+    fn new(cfg: ElasticsearchConfig) -> Self {
+        let (queue, mut c) = RingBuffer::new(RING_SIZE).split();
+        let mut bulk = Bulk::new(&cfg);
+        // bulk requests block on the cluster, the loop runs on its own thread
+        thread::spawn(move || bulk.write_out(&mut c));
+        Self { cfg, queue }
+    }
+}
+
+impl IOutput for ElasticsearchOutput {
+    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
+        self.write_with_ack(channel, item, Ack::none())
+    }
+
+    fn write_with_ack(&mut self, _: &str, item: Item, ack: Ack) -> Result<()> {
+        let index = render_index(&self.cfg.index, &Envelope::new(&item))?;
+        if self.queue.push(BulkItem::new(index, &item, ack)).is_err() {
+            return Err(Box::new(QueueFull));
+        }
+        Ok(())
+    }
+}
+
+// sends _bulk requests, items the cluster rejected as overloaded are sent again,
+// a request too large for the cluster is split
+struct Bulk {
+    agent: ureq::Agent,
+    urls: Vec<String>,
+    current: usize,
+    auth: Option<String>,
+    batching: Batching,
+    backoff: Backoff,
+}
+
+impl Bulk {
+    fn new(cfg: &ElasticsearchConfig) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout(cfg.batching.timeout)
+                .build(),
+            urls: cfg.urls.clone(),
+            current: 0,
+            auth: cfg.auth.clone(),
+            batching: cfg.batching.clone(),
+            backoff: Backoff::new(),
+        }
+    }
+
+    fn write_out(&mut self, cons: &mut RConsumer<BulkItem>) {
+        let batching = self.batching.clone();
+        batching.write_out(cons, |item| item.doc.len(), |batch| self.flush(batch));
+    }
+
+    // the acks of the batch are held until every item was indexed or rejected
+    // for good and fired in queue (offset) order
+    fn flush(&mut self, items: Vec<BulkItem>) {
+        let acks = items
+            .iter()
+            .map(|item| item.ack.clone())
+            .collect::<Vec<Ack>>();
+        self.index(items);
+        for ack in acks {
+            ack.ack();
+        }
+    }
+
+    fn index(&mut self, mut items: Vec<BulkItem>) {
+        while !items.is_empty() {
+            let statuses = match self.bulk(&items) {
+                Ok(statuses) => statuses,
+                Err(e) => {
+                    let url = &self.urls[self.current];
+                    eprintln!("elasticsearch bulk to {:?} error: {}", url, e);
+                    self.current = (self.current + 1) % self.urls.len();
+                    thread::sleep(self.backoff.fail());
+                    continue;
+                }
+            };
+            if items.len() > 1 && statuses.iter().all(|(status, _)| *status == 413) {
+                let rest = items.split_off(items.len() / 2);
+                self.index(items);
+                self.index(rest);
+                return;
+            }
+
+            let mut retry = Vec::new();
+            for (item, (status, reason)) in items.into_iter().zip(statuses) {
+                match status {
+                    429 | 500..=599 => retry.push(item),
+                    200..=299 => {}
+                    _ => {
+                        // mapping and other client errors never succeed, drop the document.
+                        // a single document over the request size limit ends here too
+                        eprintln!(
+                            "elasticsearch rejected document for {:?} status {}: {}",
+                            item.index, status, reason
+                        );
+                    }
+                }
+            }
+            items = retry;
+            if items.is_empty() {
+                self.backoff.reset();
+            } else {
+                thread::sleep(self.backoff.fail());
+            }
+        }
+    }
+
+    // status and error reason of every item, in request order. a request the
+    // cluster refused for good gives its status to every item, an overloaded
+    // or unreachable cluster is an error and the request is sent again
+    fn bulk(&self, items: &[BulkItem]) -> Result<Vec<(u16, String)>> {
+        let mut body = String::new();
+        for item in items {
+            body.push_str(&json!({"index": {"_index": item.index}}).to_string());
+            body.push('\n');
+            body.push_str(&item.doc);
+            body.push('\n');
+        }
+
+        let mut req = self
+            .agent
+            .post(&format!("{}/_bulk", self.urls[self.current]))
+            .set("Content-Type", "application/x-ndjson");
+        if let Some(auth) = &self.auth {
+            req = req.set("Authorization", auth);
+        }
+        let res = match req.send_string(&body) {
+            Ok(res) => res,
+            Err(ureq::Error::Status(code, res)) => {
+                let reason = res.into_string().unwrap_or_default();
+                if code == 429 || code >= 500 {
+                    return Err(format!("status {}: {}", code, reason).into());
+                }
+                return Ok(vec![(code, reason); items.len()]);
+            }
+            Err(e) => return Err(Box::new(e)),
+        };
+
+        let res = serde_json::from_str::<Value>(&res.into_string()?)?;
+        if res["errors"] == json!(false) {
+            return Ok(vec![(200, String::new()); items.len()]);
+        }
+        let statuses = match res["items"].as_array() {
+            Some(results) if results.len() == items.len() => results
+                .iter()
+                .map(|result| {
+                    // the single key is the action, index here
+                    let result = result
+                        .as_object()
+                        .and_then(|o| o.values().next())
+                        .unwrap_or(&Value::Null);
+                    let status = result["status"].as_u64().unwrap_or(500) as u16;
+                    (status, result["error"].to_string())
+                })
+                .collect::<Vec<(u16, String)>>(),
+            _ => return Err(format!("unexpected bulk response: {}", res).into()),
+        };
+        Ok(statuses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_index, Bulk, BulkItem, ElasticsearchConfig};
+    use crate::stand_in;
+    use crate::{Envelope, Uri};
+    use common::{Ack, Item};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn config_from_uri() {
+        let cfg = ElasticsearchConfig::from_uri(
+            &Uri::parse("es://127.0.0.1:9200,127.0.0.2:9200/logs-{ns}-{date}?user=elastic&password=changeme&tls=true&batch=2").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.urls,
+            vec!["https://127.0.0.1:9200", "https://127.0.0.2:9200"]
+        );
+        assert_eq!(cfg.auth, Some("Basic ZWxhc3RpYzpjaGFuZ2VtZQ==".to_string()));
+        assert_eq!(cfg.batching.batch, 2);
+
+        for channel in &[
+            "es://127.0.0.1:9200",
+            "es:///logs",
+            "es://127.0.0.1:9200/logs-{namespace}",
+            "es://127.0.0.1:9200/logs?password=changeme",
+            "es://127.0.0.1:9200/logs?user=elastic&password_file=/nonexistent",
+        ] {
+            assert!(ElasticsearchConfig::from_uri(&Uri::parse(channel).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn render_index_works() {
+        let item = Item::from(r#"{"custom":{"ns":"Finance Dev"},"message":"hello"}"#);
+        let index = render_index("logs-{ns}-{date}", &Envelope::new(&item)).unwrap();
+        assert!(index.starts_with("logs-finance_dev-20"));
+    }
+
+    #[test]
+    fn bulk_retries_failed_items() {
+        let (port, rx) = stand_in::serve(vec![
+            (
+                200,
+                "",
+                r#"{"errors":true,"items":[{"index":{"status":201}},{"index":{"status":429,"error":{"type":"es_rejected_execution_exception"}}},{"index":{"status":400,"error":{"type":"mapper_parsing_exception"}}}]}"#,
+            ),
+            (
+                200,
+                "",
+                r#"{"errors":false,"items":[{"index":{"status":201}}]}"#,
+            ),
+        ]);
+        let cfg = ElasticsearchConfig::from_uri(
+            &Uri::parse(&format!(
+                "es://127.0.0.1:{}/logs-{{ns}}?user=elastic&password=changeme",
+                port
+            ))
+            .unwrap(),
+        )
+        .unwrap();
+        let mut bulk = Bulk::new(&cfg);
+
+        let acked = Arc::new(Mutex::new(vec![]));
+        let items = (0..3)
+            .map(|i| {
+                let acked = acked.clone();
+                let item = Item::from(
+                    format!(r#"{{"custom":{{"ns":"default"}},"message":"m{}"}}"#, i).as_str(),
+                );
+                BulkItem::new(
+                    "logs-default".to_string(),
+                    &item,
+                    Ack::new(move || acked.lock().unwrap().push(i)),
+                )
+            })
+            .collect::<Vec<BulkItem>>();
+        bulk.flush(items);
+        // all acked once the retry went through, in queue order
+        assert_eq!(*acked.lock().unwrap(), vec![0, 1, 2]);
+
+        let req = rx.recv().unwrap();
+        let body = req.text();
+        assert!(req.head.starts_with("POST /_bulk"));
+        assert_eq!(
+            req.header("authorization"),
+            Some("Basic ZWxhc3RpYzpjaGFuZ2VtZQ==".to_string())
+        );
+        assert_eq!(body.lines().count(), 6);
+        assert!(body.starts_with(r#"{"index":{"_index":"logs-default"}}"#));
+
+        // only the overloaded item is sent again
+        let body = rx.recv().unwrap().text();
+        assert_eq!(body.lines().count(), 2);
+        assert!(body.contains(r#""message":"m1""#));
+    }
+
+    fn items(n: usize, acked: &Arc<Mutex<Vec<usize>>>) -> Vec<BulkItem> {
+        (0..n)
+            .map(|i| {
+                let acked = acked.clone();
+                BulkItem::new(
+                    "logs".to_string(),
+                    &Item::from(format!("m{}", i).as_str()),
+                    Ack::new(move || acked.lock().unwrap().push(i)),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bulk_splits_too_large_requests() {
+        let (port, rx) = stand_in::serve(vec![
+            (413, "", ""),
+            (200, "", r#"{"errors":false,"items":[]}"#),
+            (413, "", ""),
+            (200, "", r#"{"errors":false,"items":[]}"#),
+            (413, "", ""),
+        ]);
+        let cfg = ElasticsearchConfig::from_uri(
+            &Uri::parse(&format!("es://127.0.0.1:{}/logs", port)).unwrap(),
+        )
+        .unwrap();
+        let mut bulk = Bulk::new(&cfg);
+
+        let acked = Arc::new(Mutex::new(vec![]));
+        bulk.flush(items(3, &acked));
+        assert_eq!(*acked.lock().unwrap(), vec![0, 1, 2]);
+
+        // 3 items, then the first one, then the last two, then each of them
+        let sizes = (0..5)
+            .map(|_| rx.recv().unwrap().text().lines().count() / 2)
+            .collect::<Vec<usize>>();
+        assert_eq!(sizes, vec![3, 1, 2, 1, 1]);
+    }
+
+    #[test]
+    fn bulk_drops_rejected_requests() {
+        let (port, rx) =
+            stand_in::serve(vec![(503, "", ""), (400, "", r#"{"error":"bad request"}"#)]);
+        let cfg = ElasticsearchConfig::from_uri(
+            &Uri::parse(&format!("es://127.0.0.1:{}/logs", port)).unwrap(),
+        )
+        .unwrap();
+        let mut bulk = Bulk::new(&cfg);
+
+        // the unavailable cluster is asked again, the bad request is not
+        let acked = Arc::new(Mutex::new(vec![]));
+        bulk.flush(items(2, &acked));
+        assert_eq!(*acked.lock().unwrap(), vec![0, 1]);
+        assert_eq!(rx.iter().count(), 2);
+    }
+}
@@ -0,0 +1,117 @@
+use common::{Item, Result};
+use serde_json::Value;
+
+// read access to the record envelope built by the file reader
+// {"custom":{"nodeId":pod,"ns":..,"nodeName":..,"container":..,"serviceName":..},"message":..}
+pub struct Envelope<'a>(Option<&'a Value>);
+
+impl<'a> Envelope<'a> {
+    pub fn new(item: &'a Item) -> Self {
+        match item {
+            Item::JSON(value) => Self(Some(value)),
+            Item::Default(_) => Self(None),
+        }
+    }
+
+    fn field(&self, parent: Option<&str>, key: &str) -> &'a str {
+        let value = match (self.0, parent) {
+            (Some(v), Some(parent)) => v.get(parent).and_then(|v| v.get(key)),
+            (Some(v), None) => v.get(key),
+            _ => None,
+        };
+        value.and_then(|v| v.as_str()).unwrap_or("")
+    }
+
+    pub fn ns(&self) -> &'a str {
+        self.field(Some("custom"), "ns")
+    }
+
+    pub fn pod(&self) -> &'a str {
+        self.field(Some("custom"), "nodeId")
+    }
+
+    pub fn container(&self) -> &'a str {
+        self.field(Some("custom"), "container")
+    }
+
+    pub fn service_name(&self) -> &'a str {
+        self.field(Some("custom"), "serviceName")
+    }
+
+    pub fn node(&self) -> &'a str {
+        self.field(Some("custom"), "nodeName")
+    }
+
+    // fills the {ns}, {pod}, {container}, {service_name} and {node} fields of a
+    // template, `clean` maps characters the sink does not allow
+    pub fn render<F>(&self, template: &str, clean: F) -> Result<String>
+    where
+        F: Fn(char) -> char,
+    {
+        let mut res = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            res.push_str(&rest[..start]);
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => return Err(format!("template `{}` has an unclosed `{{`", template).into()),
+            };
+            let value = match &rest[start + 1..end] {
+                "ns" => self.ns(),
+                "pod" => self.pod(),
+                "container" => self.container(),
+                "service_name" => self.service_name(),
+                "node" => self.node(),
+                name => {
+                    return Err(
+                        format!("template `{}` has unknown field `{{{}}}`", template, name).into(),
+                    )
+                }
+            };
+            if value.is_empty() {
+                res.push_str("unknown");
+            }
+            res.extend(value.chars().map(&clean));
+            rest = &rest[end + 1..];
+        }
+        res.push_str(rest);
+        Ok(res)
+    }
+
+    pub fn message(&self) -> &'a str {
+        self.field(None, "message")
+    }
+
+    pub fn stream(&self) -> &'a str {
+        self.field(None, "stream")
+    }
+
+    pub fn time(&self) -> &'a str {
+        self.field(None, "time")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Envelope;
+    use common::Item;
+
+    #[test]
+    fn envelope_works() {
+        let item = Item::from(
+            r#"{"custom":{"nodeId":"web-0","ns":"default","nodeName":"node1","container":"web","serviceName":"web-svc"},"message":"hello","stream":"stderr"}"#,
+        );
+        let envelope = Envelope::new(&item);
+        assert_eq!(envelope.ns(), "default");
+        assert_eq!(envelope.pod(), "web-0");
+        assert_eq!(envelope.container(), "web");
+        assert_eq!(envelope.service_name(), "web-svc");
+        assert_eq!(envelope.node(), "node1");
+        assert_eq!(envelope.message(), "hello");
+        assert_eq!(envelope.stream(), "stderr");
+        assert_eq!(envelope.time(), "");
+
+        let item = Item::from("plain line");
+        assert_eq!(Envelope::new(&item).pod(), "");
+    }
+}
@@ -0,0 +1,152 @@
+use super::{Ack, QueueFull, Result, OUTPUTS};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const LANE_SIZE: usize = 10240;
+const MAX_BACKPRESSURE: Duration = Duration::from_secs(1);
+
+// one lane per channel in front of its output, a channel that falls behind
+// fills its own lane while the other channels keep going
+type Lane = SyncSender<(String, Ack)>;
+
+static LANES: Lazy<Mutex<HashMap<String, Lane>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn lane(channel: &str) -> Result<Lane> {
+    let mut lanes = match LANES.lock() {
+        Ok(lanes) => lanes,
+        Err(e) => return Err(format!("{:?}", e).into()),
+    };
+    if let Some(lane) = lanes.get(channel) {
+        return Ok(lane.clone());
+    }
+    let (tx, rx) = sync_channel::<(String, Ack)>(LANE_SIZE);
+    let name = channel.to_string();
+    thread::spawn(move || {
+        for (line, ack) in rx {
+            let mut wait = Duration::from_millis(1);
+            loop {
+                let res = match OUTPUTS.lock() {
+                    Ok(mut ot) => ot.output_with_ack(&name, &line, ack.clone()),
+                    Err(_) => return,
+                };
+                match res {
+                    Err(e) if e.is::<QueueFull>() => {
+                        thread::sleep(wait);
+                        wait = (wait * 2).min(MAX_BACKPRESSURE);
+                    }
+                    Err(e) => {
+                        // not acked, the offset of this output stays before the line
+                        eprintln!("fan out output {:?} error, line lost: {:?}", name, e);
+                        break;
+                    }
+                    Ok(_) => break,
+                }
+            }
+        }
+    });
+    lanes.insert(channel.to_string(), tx.clone());
+    Ok(tx)
+}
+
+// hands the line to the lane of `channel`, `ack` is called once the output delivered it.
+// a full lane returns `QueueFull`, the caller keeps the line and retries
+pub fn fan_out(channel: &str, line: &str, ack: Ack) -> Result<()> {
+    if line.is_empty() {
+        ack.ack();
+        return Ok(());
+    }
+    match lane(channel)?.try_send((line.to_string(), ack)) {
+        Ok(_) => Ok(()),
+        Err(TrySendError::Full(_)) => Err(Box::new(QueueFull)),
+        Err(TrySendError::Disconnected(_)) => {
+            Err(format!("fan out output {:?} lane closed", channel).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fan_out, LANE_SIZE};
+    use crate::{IOutput, Output, QueueFull, OUTPUTS};
+    use common::{Ack, Item, Result};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
+
+    // holds every record until the test releases it
+    struct Gate(Arc<Mutex<Vec<Ack>>>);
+
+    impl IOutput for Gate {
+        fn write(&mut self, _: &str, _: Item) -> Result<()> {
+            Ok(())
+        }
+
+        fn write_with_ack(&mut self, _: &str, _: Item, ack: Ack) -> Result<()> {
+            self.0.lock().unwrap().push(ack);
+            Ok(())
+        }
+    }
+
+    // never takes a record
+    struct Full;
+
+    impl IOutput for Full {
+        fn write(&mut self, _: &str, _: Item) -> Result<()> {
+            Err(Box::new(QueueFull))
+        }
+
+        fn write_with_ack(&mut self, _: &str, _: Item, _: Ack) -> Result<()> {
+            Err(Box::new(QueueFull))
+        }
+    }
+
+    #[test]
+    fn fan_out_acks_after_output() {
+        let held = Arc::new(Mutex::new(vec![]));
+        if let Ok(mut ot) = OUTPUTS.lock() {
+            ot.registry_output("fan_out_gate", Output::new(Gate(held.clone())));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let ack = Ack::new(move || {
+            tx.lock().unwrap().send(()).unwrap();
+        });
+        fan_out("fan_out_gate", "hello", ack).unwrap();
+
+        // the gate holds the line
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        for _ in 0..100 {
+            if !held.lock().unwrap().is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        for ack in held.lock().unwrap().drain(..) {
+            ack.ack();
+        }
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn full_lane_is_queue_full() {
+        if let Ok(mut ot) = OUTPUTS.lock() {
+            ot.registry_output("fan_out_full", Output::new(Full));
+        }
+        // one line is stuck at the output, the lane holds the rest
+        let mut sent = 0;
+        while sent <= LANE_SIZE + 1 {
+            match fan_out("fan_out_full", "hello", Ack::none()) {
+                Ok(_) => sent += 1,
+                Err(e) => {
+                    assert!(e.is::<QueueFull>());
+                    break;
+                }
+            }
+        }
+        assert!(sent == LANE_SIZE || sent == LANE_SIZE + 1);
+    }
+}
@@ -0,0 +1,404 @@
+use super::{Envelope, IOutput, Item, Output, Result, Uri};
+use chrono::Utc;
+use flate2::{write::GzEncoder, Compression};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_SIZE: &str = "100M";
+const DEFAULT_KEEP: usize = 5;
+const DEFAULT_MAX_OPEN: usize = 256;
+const DEFAULT_IDLE_MS: u64 = 300_000;
+const EVICT_INTERVAL: Duration = Duration::from_secs(10);
+const HOUR_FORMAT: &str = "%Y%m%d%H";
+
+pub(crate) fn factory(uri: &Uri) -> Result<Box<dyn IOutput>> {
+    let cfg = FileOutputConfig::from_uri(uri)?;
+    Ok(Box::new(Output::new(FileOutput::new(cfg))))
+}
+
+// 1024, 64K, 100M, 1G
+fn parse_size(size: &str) -> Result<u64> {
+    let (num, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => (&size[..i], &size[i..]),
+        None => (size, ""),
+    };
+    let unit = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("file output size `{}` is invalid", size).into()),
+    };
+    match num.parse::<u64>() {
+        Ok(num) => Ok(num * unit),
+        Err(_) => Err(format!("file output size `{}` is invalid", size).into()),
+    }
+}
+
+#[derive(Clone, Debug)]
+struct FileOutputConfig {
+    path: String,
+    max_size: u64,
+    hourly: bool,
+    gzip: bool,
+    keep: usize,
+    max_open: usize,
+    idle: Duration,
+}
+
+impl FileOutputConfig {
+    // channel = file:///data/harvest/{ns}/{pod}.log?max_size=100M&hourly=true&gzip=true&keep=5
+    // path fields: {ns}, {pod}, {container}, {service_name}, {node}, max_size=0 turns size rotation off
+    // at most max_open files stay open, a file not written for idle_ms is closed
+    fn from_uri(uri: &Uri) -> Result<Self> {
+        if uri.target.is_empty() || uri.target.ends_with('/') {
+            return Err(format!("file output `{}` expects a file path", uri.target).into());
+        }
+        Envelope::new(&Item::Default(String::new())).render(&uri.target, |c| c)?;
+        let max_open = uri.param_or("max_open", DEFAULT_MAX_OPEN)?;
+        if max_open == 0 {
+            return Err("file output max_open must be greater than 0".into());
+        }
+        Ok(Self {
+            path: uri.target.clone(),
+            max_size: parse_size(uri.param("max_size").unwrap_or(DEFAULT_MAX_SIZE))?,
+            hourly: uri.param_or("hourly", false)?,
+            gzip: uri.param_or("gzip", false)?,
+            keep: uri.param_or("keep", DEFAULT_KEEP)?,
+            max_open,
+            idle: Duration::from_millis(uri.param_or("idle_ms", DEFAULT_IDLE_MS)?),
+        })
+    }
+}
+
+struct Segment {
+    file: File,
+    size: u64,
+    hour: String,
+    last: Instant,
+}
+
+impl Segment {
+    fn open(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            size: file.metadata()?.len(),
+            hour: Utc::now().format(HOUR_FORMAT).to_string(),
+            last: Instant::now(),
+            file,
+        })
+    }
+}
+
+// one appended file per resolved path, rotated to <path>.<time>[.gz]
+pub(crate) struct FileOutput {
+    cfg: FileOutputConfig,
+    segments: HashMap<PathBuf, Segment>,
+    rotated: Sender<(PathBuf, PathBuf)>,
+    evicted: Instant,
+}
+
+impl FileOutput {
+    fn new(cfg: FileOutputConfig) -> Self {
+        // compressing a large segment must not hold up the writers, one worker
+        // handles the rotated segments in order
+        let (rotated, rx) = channel::<(PathBuf, PathBuf)>();
+        let (gzip, keep) = (cfg.gzip, cfg.keep);
+        thread::spawn(move || {
+            for (path, segment) in rx {
+                if gzip {
+                    if let Err(e) = compress(&segment) {
+                        eprintln!("file output gzip {:?} error: {:?}", segment, e);
+                    }
+                }
+                if let Err(e) = retain(&path, keep) {
+                    eprintln!("file output retention {:?} error: {:?}", path, e);
+                }
+            }
+        });
+        Self {
+            cfg,
+            segments: HashMap::new(),
+            rotated,
+            evicted: Instant::now(),
+        }
+    }
+
+    // files of pods gone from the node are closed once idle, the least
+    // recently written one when too many are open
+    fn evict(&mut self) {
+        let idle = self.cfg.idle;
+        let expired = self
+            .segments
+            .iter()
+            .filter(|(_, segment)| segment.last.elapsed() >= idle)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<PathBuf>>();
+        for path in expired {
+            self.close(&path);
+        }
+        while self.segments.len() >= self.cfg.max_open {
+            let oldest = self
+                .segments
+                .iter()
+                .min_by_key(|(_, segment)| segment.last)
+                .map(|(path, _)| path.clone());
+            match oldest {
+                Some(path) => self.close(&path),
+                None => break,
+            }
+        }
+        self.evicted = Instant::now();
+    }
+
+    fn close(&mut self, path: &Path) {
+        if let Some(segment) = self.segments.remove(path) {
+            if let Err(e) = segment.file.sync_all() {
+                eprintln!("file output sync {:?} error: {:?}", path, e);
+            }
+        }
+    }
+
+    fn needs_rotate(&self, segment: &Segment, len: u64) -> bool {
+        if segment.size == 0 {
+            return false;
+        }
+        (self.cfg.max_size > 0 && segment.size + len > self.cfg.max_size)
+            || (self.cfg.hourly && segment.hour != Utc::now().format(HOUR_FORMAT).to_string())
+    }
+
+    fn rotate(&mut self, path: &Path) -> Result<()> {
+        self.close(path);
+        let stamp = Utc::now().format("%Y%m%dT%H%M%S%.3f").to_string();
+        let mut rotated = PathBuf::from(format!("{}.{}", path.display(), stamp));
+        let mut n = 0;
+        while rotated.exists() {
+            n += 1;
+            rotated = PathBuf::from(format!("{}.{}-{}", path.display(), stamp, n));
+        }
+        fs::rename(path, &rotated)?;
+        let _ = self.rotated.send((path.to_path_buf(), rotated));
+        Ok(())
+    }
+}
+
+fn compress(path: &Path) -> io::Result<()> {
+    let gz_path = PathBuf::from(format!("{}.gz", path.display()));
+    let mut gz = GzEncoder::new(File::create(&gz_path)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut gz)?;
+    gz.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+// keeps the `keep` newest rotated segments of `path`
+fn retain(path: &Path, keep: usize) -> io::Result<()> {
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, format!("{}.", name.to_string_lossy())),
+        _ => return Ok(()),
+    };
+    let mut rotated = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|file| file.starts_with(&name))
+        .map(|file| file.trim_end_matches(".gz").to_string())
+        .collect::<Vec<String>>();
+    // the time stamp sorts oldest first
+    rotated.sort();
+    rotated.dedup();
+    let remove = rotated.len().saturating_sub(keep);
+    for file in &rotated[..remove] {
+        for candidate in &[file.to_string(), format!("{}.gz", file)] {
+            match fs::remove_file(dir.join(candidate)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+impl IOutput for FileOutput {
+    fn write(&mut self, _: &str, item: Item) -> Result<()> {
+        let path = PathBuf::from(Envelope::new(&item).render(&self.cfg.path, |c| match c {
+            '/' | '\\' | '\0' => '_',
+            _ => c,
+        })?);
+        let mut line = item.string();
+        if !line.ends_with('\n') {
+            line.push('\n');
+        }
+
+        let rotate = match self.segments.get(&path) {
+            Some(segment) => self.needs_rotate(segment, line.len() as u64),
+            None => false,
+        };
+        if rotate {
+            self.rotate(&path)?;
+        }
+        if self.evicted.elapsed() >= EVICT_INTERVAL {
+            self.evict();
+        }
+        if !self.segments.contains_key(&path) {
+            if self.segments.len() >= self.cfg.max_open {
+                self.evict();
+            }
+            let mut segment = Segment::open(&path)?;
+            // a segment left by a previous run may already be due
+            if self.needs_rotate(&segment, line.len() as u64) {
+                drop(segment);
+                self.rotate(&path)?;
+                segment = Segment::open(&path)?;
+            }
+            self.segments.insert(path.clone(), segment);
+        }
+
+        // the file is unbuffered, the line is with the os before the record is acked
+        let segment = self.segments.get_mut(&path).unwrap();
+        segment.file.write_all(line.as_bytes())?;
+        segment.size += line.len() as u64;
+        segment.last = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_size, FileOutput, FileOutputConfig};
+    use crate::{IOutput, Uri};
+    use common::Item;
+    use std::fs;
+    use std::{thread, time::Duration};
+
+    fn files(dir: &std::path::Path) -> Vec<String> {
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn parse_size_works() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("64K").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("100mb").unwrap(), 100 * 1024 * 1024);
+        assert!(parse_size("10T").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn rotate_by_size_works() {
+        let dir = std::env::temp_dir().join("harvest_output_file");
+        let _ = fs::remove_dir_all(&dir);
+        let channel = format!(
+            "file://{}/{{ns}}/{{pod}}.log?max_size=30&keep=2&gzip=true",
+            dir.display()
+        );
+        let mut output =
+            FileOutput::new(FileOutputConfig::from_uri(&Uri::parse(&channel).unwrap()).unwrap());
+
+        for i in 0..4 {
+            let item = Item::from(
+                format!(
+                    r#"{{"custom":{{"nodeId":"web-0","ns":"default"}},"message":"m{}"}}"#,
+                    i
+                )
+                .as_str(),
+            );
+            output.write(&channel, item).unwrap();
+        }
+        let pod_dir = dir.join("default");
+        assert!(fs::read_to_string(pod_dir.join("web-0.log"))
+            .unwrap()
+            .contains(r#""message":"m3""#));
+
+        // three rotations, the oldest beyond keep is removed once compressed
+        let mut rotated = vec![];
+        for _ in 0..100 {
+            rotated = files(&pod_dir)
+                .into_iter()
+                .filter(|f| f != "web-0.log")
+                .collect::<Vec<String>>();
+            if rotated.len() == 2 && rotated.iter().all(|f| f.ends_with(".gz")) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(rotated.len(), 2);
+        assert!(rotated.iter().all(|f| f.ends_with(".gz")));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn evict_open_files() {
+        let dir = std::env::temp_dir().join("harvest_output_file_evict");
+        let _ = fs::remove_dir_all(&dir);
+        let channel = format!("file://{}/{{pod}}.log?max_open=2", dir.display());
+        let mut output =
+            FileOutput::new(FileOutputConfig::from_uri(&Uri::parse(&channel).unwrap()).unwrap());
+
+        let write = |output: &mut FileOutput, pod: &str| {
+            let item = Item::from(
+                format!(r#"{{"custom":{{"nodeId":"{}"}},"message":"m"}}"#, pod).as_str(),
+            );
+            output.write(&channel, item).unwrap();
+        };
+        for pod in &["web-0", "web-1", "web-2"] {
+            write(&mut output, pod);
+        }
+        // web-0 was the least recently written
+        let mut open = output
+            .segments
+            .keys()
+            .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        open.sort();
+        assert_eq!(open, vec!["web-1.log", "web-2.log"]);
+
+        output.cfg.idle = Duration::from_millis(0);
+        output.evict();
+        assert!(output.segments.is_empty());
+        write(&mut output, "web-0");
+        assert_eq!(
+            fs::read_to_string(dir.join("web-0.log"))
+                .unwrap()
+                .lines()
+                .count(),
+            2
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn config_from_uri() {
+        let cfg = FileOutputConfig::from_uri(
+            &Uri::parse("file:///data/harvest/{ns}/{pod}.log?hourly=true").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(cfg.path, "/data/harvest/{ns}/{pod}.log");
+        assert_eq!(cfg.max_size, 100 * 1024 * 1024);
+        assert!(cfg.hourly);
+        assert_eq!(cfg.max_open, 256);
+        assert_eq!(cfg.idle, Duration::from_secs(300));
+
+        for channel in &[
+            "file://",
+            "file:///data/",
+            "file:///data/{namespace}.log",
+            "file:///data/{pod}.log?max_open=0",
+        ] {
+            assert!(FileOutputConfig::from_uri(&Uri::parse(channel).unwrap()).is_err());
+        }
+    }
+}
@@ -0,0 +1,471 @@
+use super::backoff::Backoff;
+use super::{Ack, Envelope, IOutput, Item, Output, QueueFull, Result, Uri};
+use chrono::{DateTime, Utc};
+use ringbuf::{Consumer as RConsumer, Producer as RProducer, RingBuffer};
+use rmp::{decode, encode};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use std::{process, thread};
+
+const RING_SIZE: usize = 10240;
+const DEFAULT_BATCH: usize = 500;
+const DEFAULT_LINGER_MS: u64 = 1000;
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+// msgpack ext type of the forward protocol EventTime
+const EVENT_TIME: i8 = 0;
+
+pub(crate) fn factory(uri: &Uri) -> Result<Box<dyn IOutput>> {
+    let cfg = ForwardConfig::from_uri(uri)?;
+    Ok(Box::new(Output::new(ForwardOutput::new(cfg))))
+}
+
+#[derive(Clone, Debug)]
+struct ForwardConfig {
+    addr: String,
+    tag: String,
+    require_ack: bool,
+    batch: usize,
+    linger: Duration,
+    timeout: Duration,
+}
+
+impl ForwardConfig {
+    // channel = forward://10.200.100.200:24224/k8s.{ns}.{service_name}?require_ack=true&batch=500&linger_ms=1000
+    // tag fields: {ns}, {pod}, {container}, {service_name}, {node}
+    fn from_uri(uri: &Uri) -> Result<Self> {
+        let (addr, tag) = match uri.target.find('/') {
+            Some(i) => (&uri.target[..i], &uri.target[i + 1..]),
+            None => (uri.target.as_str(), ""),
+        };
+        if addr.to_socket_addrs().is_err() || tag.is_empty() {
+            return Err(format!("forward output `{}` expects host:port/tag", uri.target).into());
+        }
+        Envelope::new(&Item::Default(String::new())).render(tag, |c| c)?;
+
+        let batch = uri.param_or("batch", DEFAULT_BATCH)?;
+        if batch == 0 {
+            return Err("forward output batch must be greater than 0".into());
+        }
+        Ok(Self {
+            addr: addr.to_string(),
+            tag: tag.to_string(),
+            require_ack: uri.param_or("require_ack", false)?,
+            batch,
+            linger: Duration::from_millis(uri.param_or("linger_ms", DEFAULT_LINGER_MS)?),
+            timeout: Duration::from_millis(uri.param_or("timeout_ms", DEFAULT_TIMEOUT_MS)?),
+        })
+    }
+}
+
+// tags are dot separated words
+fn render_tag(template: &str, envelope: &Envelope) -> Result<String> {
+    envelope.render(template, |c| match c {
+        'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+        _ => '_',
+    })
+}
+
+struct Event {
+    tag: String,
+    // [time, record] in msgpack, ready to be packed
+    entry: Vec<u8>,
+    ack: Ack,
+}
+
+impl Event {
+    // the record looks like what fluent-bit's kubernetes filter leaves behind,
+    // {"log": ..., "stream": ..., "kubernetes": {"namespace_name": ..., ...}}
+    fn new(tag: String, item: &Item, ack: Ack) -> Result<Self> {
+        let envelope = Envelope::new(item);
+        let log = match item {
+            Item::JSON(_) => envelope.message(),
+            Item::Default(line) => line,
+        };
+        let time = DateTime::parse_from_rfc3339(envelope.time())
+            .map(|t| t.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now());
+        let kubernetes = [
+            ("namespace_name", envelope.ns()),
+            ("pod_name", envelope.pod()),
+            ("container_name", envelope.container()),
+            ("service_name", envelope.service_name()),
+            ("host", envelope.node()),
+        ]
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .copied()
+        .collect::<Vec<(&str, &str)>>();
+        let stream = envelope.stream();
+
+        let mut entry = Vec::new();
+        encode::write_array_len(&mut entry, 2)?;
+        encode::write_ext_meta(&mut entry, 8, EVENT_TIME)?;
+        entry.write_all(&(time.timestamp() as u32).to_be_bytes())?;
+        entry.write_all(&time.timestamp_subsec_nanos().to_be_bytes())?;
+
+        let fields = 1 + !stream.is_empty() as u32 + !kubernetes.is_empty() as u32;
+        encode::write_map_len(&mut entry, fields)?;
+        encode::write_str(&mut entry, "log")?;
+        encode::write_str(&mut entry, log)?;
+        if !stream.is_empty() {
+            encode::write_str(&mut entry, "stream")?;
+            encode::write_str(&mut entry, stream)?;
+        }
+        if !kubernetes.is_empty() {
+            encode::write_str(&mut entry, "kubernetes")?;
+            encode::write_map_len(&mut entry, kubernetes.len() as u32)?;
+            for (key, value) in &kubernetes {
+                encode::write_str(&mut entry, key)?;
+                encode::write_str(&mut entry, value)?;
+            }
+        }
+        Ok(Self { tag, entry, ack })
+    }
+}
+
+// [tag, entries as bin, {"size": n, "chunk": id}], the PackedForward mode
+fn pack(tag: &str, events: &[&Event], chunk: Option<&str>) -> Result<Vec<u8>> {
+    let entries = events
+        .iter()
+        .flat_map(|event| event.entry.iter().copied())
+        .collect::<Vec<u8>>();
+    let mut message = Vec::with_capacity(entries.len() + tag.len() + 64);
+    encode::write_array_len(&mut message, 3)?;
+    encode::write_str(&mut message, tag)?;
+    encode::write_bin(&mut message, &entries)?;
+    encode::write_map_len(&mut message, 1 + chunk.is_some() as u32)?;
+    encode::write_str(&mut message, "size")?;
+    encode::write_uint(&mut message, events.len() as u64)?;
+    if let Some(chunk) = chunk {
+        encode::write_str(&mut message, "chunk")?;
+        encode::write_str(&mut message, chunk)?;
+    }
+    Ok(message)
+}
+
+// the chunk id of a complete {"ack": id} answer, None while it is incomplete
+fn read_ack(buf: &[u8]) -> Option<String> {
+    let mut rd = buf;
+    let len = decode::read_map_len(&mut rd).ok()?;
+    let mut ack = None;
+    for _ in 0..len {
+        let key = read_string(&mut rd)?;
+        let value = read_string(&mut rd)?;
+        if key == "ack" {
+            ack = Some(value);
+        }
+    }
+    ack
+}
+
+fn read_string(rd: &mut &[u8]) -> Option<String> {
+    let len = decode::read_str_len(rd).ok()? as usize;
+    if rd.len() < len {
+        return None;
+    }
+    let value = String::from_utf8_lossy(&rd[..len]).to_string();
+    *rd = &rd[len..];
+    Some(value)
+}
+
+pub(crate) struct ForwardOutput {
+    cfg: ForwardConfig,
+    queue: RProducer<Event>,
+}
+
+impl ForwardOutput {
+    fn new(cfg: ForwardConfig) -> Self {
+        let (queue, mut c) = RingBuffer::new(RING_SIZE).split();
+        let mut forwarder = Forwarder::new(cfg.clone());
+        // writes and chunk acks block on the aggregator, the loop runs on its own thread
+        thread::spawn(move || forwarder.write_out(&mut c));
+        Self { cfg, queue }
+    }
+}
+
+impl IOutput for ForwardOutput {
+    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
+        self.write_with_ack(channel, item, Ack::none())
+    }
+
+    fn write_with_ack(&mut self, _: &str, item: Item, ack: Ack) -> Result<()> {
+        let tag = render_tag(&self.cfg.tag, &Envelope::new(&item))?;
+        if self.queue.push(Event::new(tag, &item, ack)?).is_err() {
+            return Err(Box::new(QueueFull));
+        }
+        Ok(())
+    }
+}
+
+// sends one PackedForward message per tag, waiting for the chunk ack when asked to
+struct Forwarder {
+    cfg: ForwardConfig,
+    conn: Option<TcpStream>,
+    chunks: u64,
+    backoff: Backoff,
+}
+
+impl Forwarder {
+    fn new(cfg: ForwardConfig) -> Self {
+        Self {
+            cfg,
+            conn: None,
+            chunks: 0,
+            backoff: Backoff::new(),
+        }
+    }
+
+    fn write_out(&mut self, cons: &mut RConsumer<Event>) {
+        let mut now = Instant::now();
+        let mut batch = Vec::with_capacity(self.cfg.batch);
+        loop {
+            match cons.pop() {
+                Some(event) => {
+                    if batch.is_empty() {
+                        now = Instant::now();
+                    }
+                    batch.push(event);
+                }
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+            if batch.len() >= self.cfg.batch
+                || (!batch.is_empty() && now.elapsed() >= self.cfg.linger)
+            {
+                self.flush(std::mem::take(&mut batch));
+            }
+        }
+    }
+
+    // unique for the process, which is all an aggregator compares it against
+    fn chunk(&mut self) -> String {
+        self.chunks += 1;
+        let now = Utc::now();
+        let mut id = Vec::with_capacity(16);
+        id.extend_from_slice(&(now.timestamp() as u32).to_be_bytes());
+        id.extend_from_slice(&now.timestamp_subsec_nanos().to_be_bytes());
+        id.extend_from_slice(&process::id().to_be_bytes());
+        id.extend_from_slice(&(self.chunks as u32).to_be_bytes());
+        base64::encode(id)
+    }
+
+    fn flush(&mut self, events: Vec<Event>) {
+        let mut tags = BTreeMap::<&str, Vec<&Event>>::new();
+        for event in &events {
+            tags.entry(&event.tag).or_default().push(event);
+        }
+        for (tag, events) in tags {
+            let chunk = match self.cfg.require_ack {
+                true => Some(self.chunk()),
+                false => None,
+            };
+            let message = match pack(tag, &events, chunk.as_deref()) {
+                Ok(message) => message,
+                Err(e) => {
+                    // packing again would fail the same way, the events are dropped
+                    // and acked so the offset moves past them
+                    eprintln!(
+                        "forward output tag {:?} encode error, {} events dropped: {:?}",
+                        tag,
+                        events.len(),
+                        e
+                    );
+                    for event in events {
+                        event.ack.ack();
+                    }
+                    continue;
+                }
+            };
+            // the same chunk id is sent again so the aggregator can tell a retry
+            while let Err(e) = self.send(&message, chunk.as_deref()) {
+                eprintln!("forward output {:?} error: {}", self.cfg.addr, e);
+                self.conn = None;
+                thread::sleep(self.backoff.fail());
+            }
+            self.backoff.reset();
+            for event in events {
+                event.ack.ack();
+            }
+        }
+    }
+
+    fn send(&mut self, message: &[u8], chunk: Option<&str>) -> Result<()> {
+        if self.conn.is_none() {
+            let addr = match self.cfg.addr.to_socket_addrs()?.next() {
+                Some(addr) => addr,
+                None => return Err(format!("{:?} not resolved", self.cfg.addr).into()),
+            };
+            let conn = TcpStream::connect_timeout(&addr, self.cfg.timeout)?;
+            conn.set_read_timeout(Some(self.cfg.timeout))?;
+            conn.set_write_timeout(Some(self.cfg.timeout))?;
+            self.conn = Some(conn);
+        }
+        let conn = self.conn.as_mut().unwrap();
+        conn.write_all(message)?;
+        let chunk = match chunk {
+            Some(chunk) => chunk,
+            None => return Ok(()),
+        };
+
+        let mut answer = Vec::new();
+        let mut buf = [0; 256];
+        loop {
+            let n = conn.read(&mut buf)?;
+            if n == 0 {
+                return Err("connection closed before the ack".into());
+            }
+            answer.extend_from_slice(&buf[..n]);
+            match read_ack(&answer) {
+                Some(ack) if ack == chunk => return Ok(()),
+                Some(ack) => return Err(format!("ack {:?} for chunk {:?}", ack, chunk).into()),
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pack, read_ack, render_tag, Event, ForwardConfig, Forwarder};
+    use crate::{Envelope, Uri};
+    use common::{Ack, Item};
+    use rmp::{decode, encode};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    const RECORD: &str = r#"{"custom":{"nodeId":"web-0","ns":"default","container":"web","serviceName":"web-svc","nodeName":"node1"},"message":"hello\n","stream":"stdout","time":"2021-03-16T09:05:01.000000001Z"}"#;
+
+    fn event(line: &str) -> Event {
+        let item = Item::from(line);
+        Event::new("k8s.default".to_string(), &item, Ack::none()).unwrap()
+    }
+
+    #[test]
+    fn config_from_uri() {
+        let cfg = ForwardConfig::from_uri(
+            &Uri::parse("forward://127.0.0.1:24224/k8s.{ns}.{service_name}?require_ack=true")
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(cfg.addr, "127.0.0.1:24224");
+        assert_eq!(cfg.tag, "k8s.{ns}.{service_name}");
+        assert!(cfg.require_ack);
+
+        for channel in &[
+            "forward://127.0.0.1:24224",
+            "forward://127.0.0.1/k8s",
+            "forward://127.0.0.1:24224/k8s.{namespace}",
+            "forward://127.0.0.1:24224/k8s?batch=0",
+        ] {
+            assert!(ForwardConfig::from_uri(&Uri::parse(channel).unwrap()).is_err());
+        }
+        let item = Item::from(RECORD);
+        assert_eq!(
+            render_tag("k8s.{ns}.{service_name}", &Envelope::new(&item)).unwrap(),
+            "k8s.default.web-svc"
+        );
+    }
+
+    #[test]
+    fn pack_works() {
+        let event = event(RECORD);
+        let mut rd = &event.entry[..];
+        assert_eq!(decode::read_array_len(&mut rd).unwrap(), 2);
+        let meta = decode::read_ext_meta(&mut rd).unwrap();
+        assert_eq!((meta.typeid, meta.size), (0, 8));
+        assert_eq!(&rd[..8], &[0x60, 0x50, 0x74, 0xbd, 0, 0, 0, 1]);
+        rd = &rd[8..];
+        assert_eq!(decode::read_map_len(&mut rd).unwrap(), 3);
+
+        let message = pack("k8s.default", &[&event, &event], Some("abc")).unwrap();
+        let mut rd = &message[..];
+        assert_eq!(decode::read_array_len(&mut rd).unwrap(), 3);
+        let mut tag = [0; 32];
+        assert_eq!(decode::read_str(&mut rd, &mut tag).unwrap(), "k8s.default");
+        let len = decode::read_bin_len(&mut rd).unwrap() as usize;
+        assert_eq!(len, event.entry.len() * 2);
+        rd = &rd[len..];
+        assert_eq!(decode::read_map_len(&mut rd).unwrap(), 2);
+    }
+
+    #[test]
+    fn read_ack_works() {
+        let mut answer = Vec::new();
+        encode::write_map_len(&mut answer, 1).unwrap();
+        encode::write_str(&mut answer, "ack").unwrap();
+        encode::write_str(&mut answer, "abc").unwrap();
+        assert_eq!(read_ack(&answer), Some("abc".to_string()));
+        assert_eq!(read_ack(&answer[..answer.len() - 1]), None);
+    }
+
+    #[test]
+    fn forward_waits_for_ack() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        // the first connection is dropped without an ack, the second acks the chunk
+        thread::spawn(move || {
+            for (n, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut buf = vec![0; 4096];
+                let len = stream.read(&mut buf).unwrap();
+                buf.truncate(len);
+                let mut rd = &buf[..];
+                decode::read_array_len(&mut rd).unwrap();
+                let mut tag = [0; 32];
+                decode::read_str(&mut rd, &mut tag).unwrap();
+                let bin = decode::read_bin_len(&mut rd).unwrap() as usize;
+                rd = &rd[bin..];
+                decode::read_map_len(&mut rd).unwrap();
+                let mut chunk = None;
+                for _ in 0..2 {
+                    let mut key = [0; 8];
+                    let key = decode::read_str(&mut rd, &mut key).unwrap().to_string();
+                    match key.as_str() {
+                        "chunk" => {
+                            let mut value = [0; 64];
+                            chunk =
+                                Some(decode::read_str(&mut rd, &mut value).unwrap().to_string());
+                        }
+                        _ => {
+                            decode::read_int::<u64, _>(&mut rd).unwrap();
+                        }
+                    }
+                }
+                let chunk = chunk.unwrap();
+                tx.send(chunk.clone()).unwrap();
+                if n > 0 {
+                    let mut answer = Vec::new();
+                    encode::write_map_len(&mut answer, 1).unwrap();
+                    encode::write_str(&mut answer, "ack").unwrap();
+                    encode::write_str(&mut answer, &chunk).unwrap();
+                    stream.write_all(&answer).unwrap();
+                }
+            }
+        });
+
+        let cfg = ForwardConfig::from_uri(
+            &Uri::parse(&format!(
+                "forward://127.0.0.1:{}/k8s?require_ack=true&timeout_ms=1000",
+                port
+            ))
+            .unwrap(),
+        )
+        .unwrap();
+        let acked = Arc::new(AtomicUsize::new(0));
+        let acked_clone = acked.clone();
+        let mut event = event(RECORD);
+        event.ack = Ack::new(move || {
+            acked_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        Forwarder::new(cfg).flush(vec![event]);
+        assert_eq!(acked.load(Ordering::SeqCst), 1);
+
+        // the retry carries the same chunk id
+        assert_eq!(rx.recv().unwrap(), rx.recv().unwrap());
+    }
+}
@@ -0,0 +1,236 @@
+use super::batch::{gzip, prefixed, Batching, Sink, Worker, HEADER_PREFIX};
+use super::{Ack, IOutput, Item, Output, QueueFull, Result, Uri};
+use ringbuf::{Producer as RProducer, RingBuffer};
+use serde_json::Value;
+use std::{str::FromStr, thread};
+
+const RING_SIZE: usize = 10240;
+const DEFAULT_BATCH: usize = 500;
+
+pub(crate) fn factory(uri: &Uri) -> Result<Box<dyn IOutput>> {
+    let cfg = HttpOutputConfig::from_uri(uri)?;
+    Ok(Box::new(Output::new(HttpOutput::new(cfg))))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Json,
+    NdJson,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "ndjson" => Ok(Format::NdJson),
+            _ => Err(format!("unknown http output format `{}`", s)),
+        }
+    }
+}
+
+impl Format {
+    fn content_type(&self) -> &str {
+        match self {
+            Format::Json => "application/json",
+            Format::NdJson => "application/x-ndjson",
+        }
+    }
+
+    fn encode(&self, records: &[String]) -> Vec<u8> {
+        match self {
+            Format::Json => format!("[{}]", records.join(",")).into_bytes(),
+            Format::NdJson => {
+                let mut body = records.join("\n");
+                body.push('\n');
+                body.into_bytes()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct HttpOutputConfig {
+    url: String,
+    format: Format,
+    headers: Vec<(String, String)>,
+    gzip: bool,
+    batching: Batching,
+}
+
+impl HttpOutputConfig {
+    // channel = https://collector:8080/ingest?format=ndjson&batch=500&batch_bytes=1048576&linger_ms=1000&gzip=true&header.X-Token=abc
+    // format = json (array) | ndjson, header.<name> adds a request header
+    fn from_uri(uri: &Uri) -> Result<Self> {
+        if uri.target.is_empty() {
+            return Err(format!("http output `{}` has no host", uri.scheme).into());
+        }
+        Ok(Self {
+            url: format!("{}://{}", uri.scheme, uri.target),
+            format: uri.param_or("format", Format::NdJson)?,
+            headers: prefixed(uri, HEADER_PREFIX)?,
+            gzip: uri.param_or("gzip", false)?,
+            batching: Batching::from_uri(uri, DEFAULT_BATCH)?,
+        })
+    }
+}
+
+pub(crate) struct HttpOutput {
+    queue: RProducer<(String, Ack)>,
+}
+
+impl HttpOutput {
+    fn new(cfg: HttpOutputConfig) -> Self {
+        let (queue, mut c) = RingBuffer::new(RING_SIZE).split();
+        let mut worker = Worker::new(Webhook(cfg.clone()), &cfg.batching);
+        // requests block on the endpoint, the loop runs on its own thread
+        thread::spawn(move || worker.write_out(&mut c));
+        Self { queue }
+    }
+}
+
+impl IOutput for HttpOutput {
+    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
+        self.write_with_ack(channel, item, Ack::none())
+    }
+
+    fn write_with_ack(&mut self, _: &str, item: Item, ack: Ack) -> Result<()> {
+        // every record is one json value, plain lines become json strings
+        let record = match item {
+            Item::JSON(value) => value.to_string(),
+            Item::Default(line) => Value::String(line).to_string(),
+        };
+        if self.queue.push((record, ack)).is_err() {
+            return Err(Box::new(QueueFull));
+        }
+        Ok(())
+    }
+}
+
+struct Webhook(HttpOutputConfig);
+
+impl Sink for Webhook {
+    type Record = String;
+
+    const NAME: &'static str = "http output";
+
+    fn url(&self) -> &str {
+        &self.0.url
+    }
+
+    fn size(record: &String) -> usize {
+        record.len() + 1
+    }
+
+    fn encode(&self, records: &[String]) -> Result<Vec<u8>> {
+        let body = self.0.format.encode(records);
+        match self.0.gzip {
+            true => gzip(&body),
+            false => Ok(body),
+        }
+    }
+
+    fn request(&self, agent: &ureq::Agent) -> ureq::Request {
+        let mut req = agent
+            .post(&self.0.url)
+            .set("Content-Type", self.0.format.content_type());
+        if self.0.gzip {
+            req = req.set("Content-Encoding", "gzip");
+        }
+        for (name, value) in &self.0.headers {
+            req = req.set(name, value);
+        }
+        req
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, HttpOutputConfig, Webhook};
+    use crate::batch::Worker;
+    use crate::{stand_in, Uri};
+    use common::Ack;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn config_from_uri() {
+        let cfg = HttpOutputConfig::from_uri(
+            &Uri::parse(
+                "https://collector:8080/ingest?format=json&batch=10&gzip=true&header.X-Token=abc",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(cfg.url, "https://collector:8080/ingest");
+        assert_eq!(cfg.format, Format::Json);
+        assert_eq!(
+            cfg.headers,
+            vec![("X-Token".to_string(), "abc".to_string())]
+        );
+        assert!(cfg.gzip);
+
+        for channel in &[
+            "http://",
+            "http://collector?format=xml",
+            "http://collector?batch=0",
+            "http://collector?header.=abc",
+        ] {
+            assert!(HttpOutputConfig::from_uri(&Uri::parse(channel).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn format_works() {
+        let records = vec![r#"{"a":1}"#.to_string(), r#""line""#.to_string()];
+        assert_eq!(
+            Format::Json.encode(&records),
+            br#"[{"a":1},"line"]"#.to_vec()
+        );
+        assert_eq!(
+            Format::NdJson.encode(&records),
+            b"{\"a\":1}\n\"line\"\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn webhook_retries_after() {
+        let (port, rx) = stand_in::serve(vec![
+            (503, "Retry-After: 0\r\n", ""),
+            (429, "", ""),
+            (200, "", "{}"),
+        ]);
+        let cfg = HttpOutputConfig::from_uri(
+            &Uri::parse(&format!(
+                "http://127.0.0.1:{}/ingest?gzip=true&header.X-Token=abc",
+                port
+            ))
+            .unwrap(),
+        )
+        .unwrap();
+        let mut worker = Worker::new(Webhook(cfg.clone()), &cfg.batching);
+
+        let acked = Arc::new(AtomicUsize::new(0));
+        let acked_clone = acked.clone();
+        let ack = Ack::new(move || {
+            acked_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        worker.flush(vec![(r#"{"message":"hello"}"#.to_string(), ack)]);
+        assert_eq!(acked.load(Ordering::SeqCst), 1);
+
+        for _ in 0..3 {
+            let req = rx.recv().unwrap();
+            assert!(req.head.starts_with("POST /ingest"));
+            assert_eq!(req.header("x-token"), Some("abc".to_string()));
+            assert_eq!(req.header("content-encoding"), Some("gzip".to_string()));
+            let mut body = String::new();
+            GzDecoder::new(&req.body[..])
+                .read_to_string(&mut body)
+                .unwrap();
+            assert_eq!(body, "{\"message\":\"hello\"}\n");
+        }
+    }
+}
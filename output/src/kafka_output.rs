@@ -1,125 +1,889 @@
-use super::{IOutput, Item, Result};
+use super::backoff::Backoff;
+use super::spool::Spool;
+use super::{Ack, Envelope, IOutput, Item, Output, QueueFull, Result, Uri};
 use async_std::task;
-use kafka::producer::{Producer, Record, RequiredAcks};
+use rdkafka::config::ClientConfig;
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
 use ringbuf::{Consumer as RConsumer, Producer as RProducer, RingBuffer};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use std::time::Instant;
 use std::{collections::HashMap, thread, time::Duration};
 
+const RING_SIZE: usize = 10240;
+const DEFAULT_BATCH: usize = 5;
+const DEFAULT_LINGER_MS: u64 = 1000;
+const DEFAULT_ACK_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_SPOOL_DIR: &str = "/var/lib/harvest/spool";
+const DEFAULT_SPOOL_MB: u64 = 1024;
+// one delivery attempt of a batch, librdkafka retries the requests inside it
+const DELIVERY_TIMEOUT_MS: u64 = 10000;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// a topic not written for this long gives its queue and delivery thread back
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const EVICT_INTERVAL: Duration = Duration::from_secs(10);
+
+pub(crate) fn factory(uri: &Uri) -> Result<Box<dyn IOutput>> {
+    let cfg = KafkaOutputConfig::from_uri(uri)?;
+    // connect once, bad brokers or certificates fail the registration not the writer
+    let kp = cfg.producer()?;
+    if let Err(e) = kp.client().fetch_metadata(None, CONNECT_TIMEOUT) {
+        return Err(format!("kafka output {:?} connect error: {}", cfg.broker, e).into());
+    }
+    let mut ko = KafkaOuput::new(cfg, kp);
+    ko.replay_leftover();
+    Ok(Box::new(Output::new(ko)))
+}
+
+// record key, records with the same key land in the same partition in order
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PartitionKey {
+    None,
+    Service,
+    Pod,
+    Container,
+}
+
+impl std::str::FromStr for PartitionKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(PartitionKey::None),
+            "service" => Ok(PartitionKey::Service),
+            "pod" => Ok(PartitionKey::Pod),
+            "container" => Ok(PartitionKey::Container),
+            _ => Err(format!("unknown kafka partition key `{}`", s)),
+        }
+    }
+}
+
+impl PartitionKey {
+    // an empty key leaves the record to the round robin partitioner
+    fn key(&self, item: &Item) -> String {
+        let envelope = Envelope::new(item);
+        match self {
+            PartitionKey::None => "".to_string(),
+            PartitionKey::Service => envelope.service_name().to_string(),
+            PartitionKey::Pod if envelope.pod().is_empty() => "".to_string(),
+            PartitionKey::Pod => format!("{}/{}", envelope.ns(), envelope.pod()),
+            PartitionKey::Container if envelope.pod().is_empty() => "".to_string(),
+            PartitionKey::Container => format!(
+                "{}/{}/{}",
+                envelope.ns(),
+                envelope.pod(),
+                envelope.container()
+            ),
+        }
+    }
+}
+
+// librdkafka `acks` value
+fn parse_acks(acks: &str) -> Result<&'static str> {
+    match acks {
+        "0" | "none" => Ok("0"),
+        "1" | "one" => Ok("1"),
+        "-1" | "all" => Ok("all"),
+        _ => Err(format!("unknown kafka acks `{}`", acks).into()),
+    }
+}
+
+// librdkafka `compression.type` value
+fn parse_compression(compression: &str) -> Result<&'static str> {
+    match compression {
+        "none" => Ok("none"),
+        "gzip" => Ok("gzip"),
+        "snappy" => Ok("snappy"),
+        _ => Err(format!("unknown kafka compression `{}`", compression).into()),
+    }
+}
+
+// record headers routing consumers can read without parsing the payload
+fn record_headers(envelope: &Envelope) -> OwnedHeaders {
+    [
+        ("ns", envelope.ns()),
+        ("pod", envelope.pod()),
+        ("container", envelope.container()),
+        ("service_name", envelope.service_name()),
+        ("node", envelope.node()),
+    ]
+    .iter()
+    .fold(OwnedHeaders::new(), |headers, (key, value)| {
+        headers.insert(Header {
+            key,
+            value: Some(*value),
+        })
+    })
+}
+
+// topic of a record, kafka topics only allow [a-zA-Z0-9._-]
+fn render_topic(template: &str, envelope: &Envelope) -> Result<String> {
+    envelope.render(template, |c| match c {
+        'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+        _ => '_',
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct TlsConfig {
+    ca: Option<String>,
+    cert: Option<String>,
+    key: Option<String>,
+    verify_hostname: bool,
+}
+
+impl TlsConfig {
+    // tls=true, tls_ca=/path/ca.pem, tls_cert=/path/client.pem, tls_key=/path/client.key,
+    // tls_verify_hostname=false
+    fn from_uri(uri: &Uri) -> Result<Option<Self>> {
+        let path = |key: &str| uri.param(key).map(|v| v.to_string());
+        let (ca, cert, key) = (path("tls_ca"), path("tls_cert"), path("tls_key"));
+        if !uri.param_or("tls", false)? && ca.is_none() && cert.is_none() && key.is_none() {
+            return Ok(None);
+        }
+        if cert.is_some() != key.is_some() {
+            return Err("kafka output tls client auth needs both cert and key".into());
+        }
+        Ok(Some(Self {
+            ca,
+            cert,
+            key,
+            verify_hostname: uri.param_or("tls_verify_hostname", true)?,
+        }))
+    }
+
+    // files are loaded when the producer is created, a bad path fails the registration
+    fn apply(&self, config: &mut ClientConfig) {
+        if let Some(ca) = &self.ca {
+            config.set("ssl.ca.location", ca);
+        }
+        if let (Some(cert), Some(key)) = (&self.cert, &self.key) {
+            config.set("ssl.certificate.location", cert);
+            config.set("ssl.key.location", key);
+        }
+        config.set(
+            "ssl.endpoint.identification.algorithm",
+            if self.verify_hostname {
+                "https"
+            } else {
+                "none"
+            },
+        );
+    }
+}
+
+#[derive(Clone, PartialEq)]
+struct SaslConfig {
+    mechanism: &'static str,
+    username: String,
+    password: String,
+}
+
+impl std::fmt::Debug for SaslConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SaslConfig")
+            .field("mechanism", &self.mechanism)
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+impl SaslConfig {
+    // sasl = plain | scram-sha-256 | scram-sha-512, sasl_file=/path/credentials
+    // holding `username:password`, read at registration so a bad file fails it
+    fn from_uri(uri: &Uri) -> Result<Option<Self>> {
+        let mechanism = match uri.param("sasl") {
+            Some("plain") => "PLAIN",
+            Some("scram-sha-256") => "SCRAM-SHA-256",
+            Some("scram-sha-512") => "SCRAM-SHA-512",
+            Some(mechanism) => {
+                return Err(format!("unknown kafka sasl mechanism `{}`", mechanism).into())
+            }
+            None => return Ok(None),
+        };
+        let path = match uri.param("sasl_file") {
+            Some(path) => path,
+            None => return Err("kafka output sasl needs a sasl_file with the credentials".into()),
+        };
+        let credentials = match std::fs::read_to_string(path) {
+            Ok(credentials) => credentials,
+            Err(e) => return Err(format!("kafka output sasl file {:?} error: {}", path, e).into()),
+        };
+        match credentials
+            .trim_end_matches(&['\r', '\n'][..])
+            .split_once(':')
+        {
+            Some((username, password)) if !username.is_empty() => Ok(Some(Self {
+                mechanism,
+                username: username.to_string(),
+                password: password.to_string(),
+            })),
+            _ => Err(format!(
+                "kafka output sasl file {:?} expects username:password",
+                path
+            )
+            .into()),
+        }
+    }
+
+    fn apply(&self, config: &mut ClientConfig) {
+        config
+            .set("sasl.mechanisms", self.mechanism)
+            .set("sasl.username", &self.username)
+            .set("sasl.password", &self.password);
+    }
+}
+
 #[derive(Clone, Debug)]
 struct KafkaOutputConfig {
+    // the channel without its query, spools of its topics are named after it
+    channel: String,
     broker: Vec<String>,
     topic: String,
+    key: PartitionKey,
+    headers: bool,
+    acks: &'static str,
+    ack_timeout: Duration,
+    batch: usize,
+    linger: Duration,
+    compression: &'static str,
+    client_id: Option<String>,
+    tls: Option<TlsConfig>,
+    sasl: Option<SaslConfig>,
+    retries: u32,
+    spool_dir: String,
+    spool_bytes: u64,
 }
 
+impl KafkaOutputConfig {
+    // channel =  kafka:topic@10.200.100.200:9092,10.200.100.201:9092?key=pod&acks=all&batch=500
+    // topic may be a template, kafka:logs-{ns}-{service_name}@10.200.100.200:9092
+    // key = none | service | pod | container, headers=true adds ns, pod, container,
+    // service_name and node record headers
+    // acks = none | one | all, ack_timeout_ms, batch, linger_ms, compression = none | gzip | snappy, client_id
+    // retries: failed sends before a batch goes to the spool in spool_dir, at most spool_mb
+    fn from_uri(uri: &Uri) -> Result<Self> {
+        let (topic, broker) = match uri.target.find('@') {
+            Some(index) => (&uri.target[..index], &uri.target[index + 1..]),
+            None => {
+                return Err(format!("kafka output `{}` expects topic@brokers", uri.target).into())
+            }
+        };
+        let broker = broker
+            .split(',')
+            .filter(|b| !b.is_empty())
+            .map(|b| b.to_string())
+            .collect::<Vec<String>>();
+        if topic.is_empty() || broker.is_empty() {
+            return Err(format!("kafka output `{}` expects topic@brokers", uri.target).into());
+        }
+        render_topic(topic, &Envelope::new(&Item::Default(String::new())))?;
+        let batch = uri.param_or("batch", DEFAULT_BATCH)?;
+        if batch == 0 {
+            return Err("kafka output batch must be greater than 0".into());
+        }
+        Ok(Self {
+            channel: format!("{}:{}", uri.scheme, uri.target),
+            broker,
+            topic: topic.to_string(),
+            key: uri.param_or("key", PartitionKey::None)?,
+            headers: uri.param_or("headers", false)?,
+            acks: parse_acks(uri.param("acks").unwrap_or("one"))?,
+            ack_timeout: Duration::from_millis(
+                uri.param_or("ack_timeout_ms", DEFAULT_ACK_TIMEOUT_MS)?,
+            ),
+            batch,
+            linger: Duration::from_millis(uri.param_or("linger_ms", DEFAULT_LINGER_MS)?),
+            compression: parse_compression(uri.param("compression").unwrap_or("none"))?,
+            client_id: uri.param("client_id").map(|id| id.to_string()),
+            tls: TlsConfig::from_uri(uri)?,
+            sasl: SaslConfig::from_uri(uri)?,
+            retries: uri.param_or("retries", DEFAULT_RETRIES)?,
+            spool_dir: uri
+                .param("spool_dir")
+                .unwrap_or(DEFAULT_SPOOL_DIR)
+                .to_string(),
+            spool_bytes: uri.param_or("spool_mb", DEFAULT_SPOOL_MB)? * 1024 * 1024,
+        })
+    }
+
+    // one producer serves every topic of the channel, it connects in its own threads
+    fn producer(&self) -> Result<FutureProducer> {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", self.broker.join(","))
+            .set("acks", self.acks)
+            .set("compression.type", self.compression)
+            .set(
+                "request.timeout.ms",
+                self.ack_timeout.as_millis().to_string(),
+            )
+            .set(
+                "message.timeout.ms",
+                DELIVERY_TIMEOUT_MS
+                    .max(self.ack_timeout.as_millis() as u64)
+                    .to_string(),
+            );
+        if let Some(client_id) = &self.client_id {
+            config.set("client.id", client_id);
+        }
+        let protocol = match (&self.tls, &self.sasl) {
+            (None, None) => "plaintext",
+            (Some(_), None) => "ssl",
+            (None, Some(_)) => "sasl_plaintext",
+            (Some(_), Some(_)) => "sasl_ssl",
+        };
+        config.set("security.protocol", protocol);
+        if let Some(tls) = &self.tls {
+            tls.apply(&mut config);
+        }
+        if let Some(sasl) = &self.sasl {
+            sasl.apply(&mut config);
+        }
+        match config.create() {
+            Ok(kp) => Ok(kp),
+            Err(e) => Err(format!("kafka output {:?} producer error: {}", self.broker, e).into()),
+        }
+    }
+}
+
+// shared by the queue of a topic and its delivery thread
+#[derive(Default)]
+struct TopicState {
+    // evicted, the thread stops once it delivered everything
+    closed: AtomicBool,
+    // records wait in the spool, the topic is not evicted
+    spooled: AtomicBool,
+}
+
+struct TopicQueue {
+    queue: RProducer<(Item, Ack)>,
+    state: Arc<TopicState>,
+    last: Instant,
+}
+
+// one delivery queue and thread per resolved topic, all on the producer of the channel
 pub(crate) struct KafkaOuput {
-    channels: HashMap<String, RProducer<Item>>,
+    cfg: KafkaOutputConfig,
+    kp: FutureProducer,
+    topics: HashMap<String, TopicQueue>,
+    idle: Duration,
+    evicted: Instant,
 }
 
 impl KafkaOuput {
-    pub fn new() -> KafkaOuput {
+    fn new(cfg: KafkaOutputConfig, kp: FutureProducer) -> KafkaOuput {
         Self {
-            channels: HashMap::new(),
+            cfg,
+            kp,
+            topics: HashMap::new(),
+            idle: IDLE_TIMEOUT,
+            evicted: Instant::now(),
         }
     }
 
-    // channel =  kafka:topic@10.200.100.200:9092,10.200.100.201:9092
-    fn parse_uri_to_producer(&self, channel: &str) -> KafkaOutputConfig {
-        let type_topic_ips = channel.split("@").collect::<Vec<&str>>();
-        let broker = type_topic_ips[1]
-            .split(",")
-            .map(|k| k.to_string())
-            .collect::<Vec<String>>();
-        let topic = type_topic_ips[0].split(":").collect::<Vec<&str>>()[1];
-        KafkaOutputConfig {
-            broker: broker,
-            topic: topic.to_string(),
+    // never waits for room while the caller holds the outputs lock
+    fn write_in(&mut self, topic: &str, item: Item, ack: Ack) -> Result<()> {
+        let topic = self.topics.get_mut(topic).unwrap();
+        topic.last = Instant::now();
+        if topic.queue.push((item, ack)).is_err() {
+            return Err(Box::new(QueueFull));
         }
+        Ok(())
     }
 
-    fn write_in(&mut self, channel: &str, item: &Item) {
-        let prod = self.channels.get_mut(channel).unwrap();
-        loop {
-            if prod.is_full() {
-                thread::sleep(Duration::from_millis(1));
-                continue;
+    // the producer is shared, a new topic only needs a queue and a thread,
+    // librdkafka loads its metadata in the background
+    fn not_exist_create(&mut self, topic: &str) -> Result<()> {
+        let cfg = self.cfg.clone();
+        let ring_buff = RingBuffer::new(RING_SIZE);
+        let (p, mut c) = ring_buff.split();
+        let state = Arc::new(TopicState::default());
+
+        let mut delivery = Delivery {
+            topic: topic.to_string(),
+            headers: cfg.headers,
+            batch: cfg.batch,
+            linger: cfg.linger,
+            retries: cfg.retries,
+            backoff: Backoff::new(),
+            kp: self.kp.clone(),
+            state: state.clone(),
+            spool: Spool::new(&cfg.spool_dir, &cfg.channel, topic, cfg.spool_bytes),
+        };
+        let key = cfg.key;
+
+        // delivery blocks on the brokers, it gets its own thread not an executor task
+        thread::spawn(move || delivery.write_out(key, &mut c));
+
+        self.topics.insert(
+            topic.to_string(),
+            TopicQueue {
+                queue: p,
+                state,
+                last: Instant::now(),
+            },
+        );
+
+        Ok(())
+    }
+
+    // spools left by a previous run get their topic back, its delivery replays them
+    fn replay_leftover(&mut self) {
+        for record in Spool::leftover(&self.cfg.spool_dir, &self.cfg.channel) {
+            match serde_json::from_str::<(String, String, String)>(&record) {
+                Ok((topic, _, _)) if !self.topics.contains_key(&topic) => {
+                    let _ = self.not_exist_create(&topic);
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("kafka spool record {:?} error: {:?}", record, e),
             }
-            prod.push(item.clone()).unwrap();
-            return;
         }
     }
 
-    async fn write_out(topic: &str, cons: &mut RConsumer<Item>, kp: &mut Producer) {
-        let count = 5;
-        let mut index = 0;
+    fn evict_idle(&mut self) {
+        let idle = self.idle;
+        self.topics.retain(|_, topic| {
+            if topic.last.elapsed() < idle || topic.state.spooled.load(Ordering::SeqCst) {
+                return true;
+            }
+            topic.state.closed.store(true, Ordering::SeqCst);
+            false
+        });
+        self.evicted = Instant::now();
+    }
+}
+
+// sends batches to the brokers, retrying with backoff and spooling to disk
+// what the brokers could not take after `retries` attempts
+struct Delivery {
+    topic: String,
+    headers: bool,
+    batch: usize,
+    linger: Duration,
+    kp: FutureProducer,
+    spool: Spool,
+    retries: u32,
+    backoff: Backoff,
+    state: Arc<TopicState>,
+}
+
+impl Delivery {
+    fn write_out(&mut self, key: PartitionKey, cons: &mut RConsumer<(Item, Ack)>) {
         let mut now = Instant::now();
-        let mut write_buffer = Vec::with_capacity(count);
+        let mut batch = Vec::with_capacity(self.batch);
+        let mut acks = Vec::with_capacity(self.batch);
         loop {
-            if cons.is_empty() {
-                thread::sleep(Duration::from_millis(1));
-                continue;
+            self.replay();
+            self.state
+                .spooled
+                .store(!self.spool.is_empty(), Ordering::SeqCst);
+            match cons.pop() {
+                Some((item, ack)) => {
+                    if batch.is_empty() {
+                        now = Instant::now();
+                    }
+                    batch.push((key.key(&item), item.string()));
+                    acks.push(ack);
+                }
+                None => {
+                    // evicted, nothing can be pushed any more
+                    if self.state.closed.load(Ordering::SeqCst)
+                        && cons.is_empty()
+                        && batch.is_empty()
+                        && self.spool.is_empty()
+                    {
+                        return;
+                    }
+                    thread::sleep(Duration::from_millis(1))
+                }
             }
-            let content = cons.pop().unwrap().string();
-            write_buffer.push(Record::from_key_value(
-                topic,
-                format!("{:?}", index),
-                content,
-            ));
-            index += 1;
-
-            if index >= write_buffer.capacity() || now.elapsed().as_secs() > 1 {
-                match kp.send_all(&write_buffer) {
+
+            if batch.len() >= self.batch || (!batch.is_empty() && now.elapsed() >= self.linger) {
+                self.deliver(&batch);
+                batch.clear();
+                // the brokers or the spool hold the batch, commit its records
+                for ack in acks.drain(..) {
+                    ack.ack();
+                }
+            }
+        }
+    }
+
+    // returns once the batch is on the brokers or on disk
+    fn deliver(&mut self, batch: &[(String, String)]) {
+        // records behind a non empty spool are spooled too, keeping their order
+        if self.spool.is_empty() {
+            for attempt in 1..=self.retries.max(1) {
+                match send(&self.kp, &self.topic, self.headers, batch) {
                     Ok(_) => {
-                        index = 0;
-                        now = Instant::now();
-                        write_buffer.clear();
+                        self.backoff.reset();
+                        return;
                     }
                     Err(e) => {
-                        eprintln!("{:?}", e);
+                        eprintln!(
+                            "kafka send to {:?} attempt {} error: {:?}",
+                            self.topic, attempt, e
+                        );
+                        let delay = self.backoff.fail();
+                        if attempt < self.retries {
+                            thread::sleep(delay);
+                        }
+                    }
+                }
+            }
+        }
+
+        // spooled records keep their topic, leftover spools are replayed by it
+        let lines = batch
+            .iter()
+            .map(|(key, value)| {
+                serde_json::to_string(&(&self.topic, key, value)).unwrap_or_default()
+            })
+            .collect::<Vec<String>>();
+        // a full spool holds the batch here, the queue fills up and collection slows down
+        while let Err(e) = self.spool.push(&lines) {
+            eprintln!("kafka spool {:?} error: {:?}", self.topic, e);
+            thread::sleep(self.backoff.fail());
+            self.replay();
+        }
+    }
+
+    fn replay(&mut self) {
+        if self.spool.is_empty() || !self.backoff.ready() {
+            return;
+        }
+        let (headers, kp) = (self.headers, &self.kp);
+        let res = self.spool.replay(self.batch, |lines| {
+            let mut topic = String::new();
+            let mut batch = Vec::with_capacity(lines.len());
+            for line in lines {
+                let (to, key, value) = match serde_json::from_str::<(String, String, String)>(line)
+                {
+                    Ok(record) => record,
+                    Err(e) => {
+                        eprintln!("kafka spool record {:?} error: {:?}", line, e);
                         continue;
                     }
+                };
+                if to != topic && !batch.is_empty() {
+                    send(kp, &topic, headers, &batch)?;
+                    batch.clear();
                 }
+                topic = to;
+                batch.push((key, value));
+            }
+            if batch.is_empty() {
+                return Ok(());
+            }
+            send(kp, &topic, headers, &batch)
+        });
+        match res {
+            Ok(_) => self.backoff.reset(),
+            Err(e) => {
+                eprintln!("kafka replay spool {:?} error: {:?}", self.topic, e);
+                self.backoff.fail();
             }
         }
     }
+}
 
-    fn not_exist_create(&mut self, channel: &str) -> Result<()> {
-        let cfg = self.parse_uri_to_producer(channel);
-        let mut kp = match Producer::from_hosts(cfg.broker)
-            .with_ack_timeout(Duration::from_secs(1))
-            .with_required_acks(RequiredAcks::One)
-            .create()
-        {
-            Ok(it) => it,
+// queues the whole batch and waits until the brokers took every record
+fn send(kp: &FutureProducer, topic: &str, headers: bool, batch: &[(String, String)]) -> Result<()> {
+    let mut deliveries = Vec::with_capacity(batch.len());
+    for (key, value) in batch {
+        let mut record = FutureRecord::<str, str>::to(topic).payload(value);
+        if !key.is_empty() {
+            record = record.key(key);
+        }
+        // spooled records only keep topic, key and value, headers come from the payload
+        if headers {
+            record = record.headers(record_headers(&Envelope::new(&Item::from(value.as_str()))));
+        }
+        match kp.send_result(record) {
+            Ok(delivery) => deliveries.push(delivery),
+            Err((e, _)) => return Err(Box::new(e)),
+        }
+    }
+    for delivery in deliveries {
+        match task::block_on(delivery) {
+            Ok(Ok(_)) => {}
+            Ok(Err((e, _))) => return Err(Box::new(e)),
             Err(e) => return Err(Box::new(e)),
-        };
+        }
+    }
+    Ok(())
+}
 
-        let ring_buff = RingBuffer::new(10240);
-        let (p, mut c) = ring_buff.split();
+impl IOutput for KafkaOuput {
+    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
+        self.write_with_ack(channel, item, Ack::none())
+    }
+
+    fn write_with_ack(&mut self, _: &str, item: Item, ack: Ack) -> Result<()> {
+        let topic = render_topic(&self.cfg.topic, &Envelope::new(&item))?;
+        if self.evicted.elapsed() >= EVICT_INTERVAL {
+            self.evict_idle();
+        }
+        if !self.topics.contains_key(&topic) {
+            self.not_exist_create(&topic)?;
+        }
+        self.write_in(&topic, item, ack)
+    }
+}
 
-        let topic = cfg.topic.clone();
+#[cfg(test)]
+mod tests {
+    use super::{
+        record_headers, render_topic, KafkaOuput, KafkaOutputConfig, PartitionKey, SaslConfig,
+        Spool, TlsConfig,
+    };
+    use crate::Envelope;
+    use crate::Uri;
+    use common::Item;
+    use rdkafka::message::Headers;
+    use std::sync::atomic::Ordering;
+    use std::{thread, time::Duration};
 
-        task::spawn(async move {
-            Self::write_out(&topic, &mut c, &mut kp).await;
-        });
+    #[test]
+    fn config_from_uri() {
+        let cfg = KafkaOutputConfig::from_uri(
+            &Uri::parse("kafka:test@10.200.100.200:9092,10.200.100.201:9092").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(cfg.topic, "test");
+        assert_eq!(
+            cfg.broker,
+            vec!["10.200.100.200:9092", "10.200.100.201:9092"]
+        );
 
-        self.channels.insert(channel.to_string(), p);
+        assert_eq!(cfg.key, PartitionKey::None);
+        assert!(!cfg.headers);
+        assert_eq!(cfg.acks, "1");
+        assert_eq!((cfg.batch, cfg.linger), (5, Duration::from_secs(1)));
 
-        Ok(())
+        for channel in &[
+            "kafka:test",
+            "kafka:@127.0.0.1:9092",
+            "kafka:test@",
+            "kafka:test@127.0.0.1:9092?key=node",
+            "kafka:test@127.0.0.1:9092?acks=2",
+            "kafka:test@127.0.0.1:9092?batch=0",
+            "kafka:test@127.0.0.1:9092?linger_ms=-1",
+            "kafka:test@127.0.0.1:9092?compression=lz4",
+            "kafka:logs-{namespace}@127.0.0.1:9092",
+            "kafka:logs-{ns@127.0.0.1:9092",
+            "kafka:test@127.0.0.1:9092?headers=yes",
+        ] {
+            assert!(KafkaOutputConfig::from_uri(&Uri::parse(channel).unwrap()).is_err());
+        }
     }
 
-    fn write_to_channel_queue(&mut self, channel: &str, item: Item) -> Result<()> {
-        self.write_in(channel, &item);
-        Ok(())
+    #[test]
+    fn config_tuning_from_uri() {
+        let cfg = KafkaOutputConfig::from_uri(
+            &Uri::parse("kafka:test@127.0.0.1:9092?acks=all&batch=500&linger_ms=200&compression=gzip&client_id=harvest-0&ack_timeout_ms=3000")
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(cfg.acks, "all");
+        assert_eq!(cfg.batch, 500);
+        assert_eq!(cfg.linger, Duration::from_millis(200));
+        assert_eq!(cfg.ack_timeout, Duration::from_secs(3));
+        assert_eq!(cfg.compression, "gzip");
+        assert_eq!(cfg.client_id, Some("harvest-0".to_string()));
     }
-}
 
-impl IOutput for KafkaOuput {
-    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
-        if !self.channels.contains_key(channel) {
-            self.not_exist_create(channel)?;
+    #[test]
+    fn partition_key_works() {
+        let item = Item::from(
+            r#"{"custom":{"nodeId":"web-0","ns":"default","container":"web","serviceName":"web-svc"},"message":"hello"}"#,
+        );
+        let key = |k: &str| k.parse::<PartitionKey>().unwrap().key(&item);
+        assert_eq!(key("none"), "");
+        assert_eq!(key("service"), "web-svc");
+        assert_eq!(key("pod"), "default/web-0");
+        assert_eq!(key("container"), "default/web-0/web");
+
+        let cfg = KafkaOutputConfig::from_uri(
+            &Uri::parse("kafka:test@127.0.0.1:9092?key=container").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(cfg.key, PartitionKey::Container);
+        assert_eq!(PartitionKey::Pod.key(&Item::from("plain")), "");
+    }
+
+    #[test]
+    fn config_security_from_uri() {
+        let cfg = KafkaOutputConfig::from_uri(
+            &Uri::parse("kafka:test@127.0.0.1:9093?tls=true&tls_verify_hostname=false").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.tls,
+            Some(TlsConfig {
+                ca: None,
+                cert: None,
+                key: None,
+                verify_hostname: false,
+            })
+        );
+        assert!(cfg.producer().is_ok());
+
+        let cfg = KafkaOutputConfig::from_uri(
+            &Uri::parse("kafka:test@127.0.0.1:9093?tls_ca=/nonexistent/ca.pem").unwrap(),
+        )
+        .unwrap();
+        assert!(cfg.producer().is_err());
+
+        for channel in &[
+            "kafka:test@127.0.0.1:9093?tls_cert=/etc/client.pem",
+            "kafka:test@127.0.0.1:9093?sasl=plain&sasl_file=/nonexistent/credentials",
+        ] {
+            assert!(KafkaOutputConfig::from_uri(&Uri::parse(channel).unwrap()).is_err());
         }
-        self.write_to_channel_queue(channel, item)
+    }
+
+    #[test]
+    fn config_sasl_from_uri() {
+        let dir = std::env::temp_dir().join("harvest_kafka_sasl");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("credentials");
+        std::fs::write(&path, "harvest:s3cr:et\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let cfg = KafkaOutputConfig::from_uri(
+            &Uri::parse(&format!(
+                "kafka:test@127.0.0.1:9093?tls=true&sasl=scram-sha-512&sasl_file={}",
+                path
+            ))
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.sasl,
+            Some(SaslConfig {
+                mechanism: "SCRAM-SHA-512",
+                username: "harvest".to_string(),
+                password: "s3cr:et".to_string(),
+            })
+        );
+        assert!(!format!("{:?}", cfg).contains("s3cr"));
+        assert!(cfg.producer().is_ok());
+
+        std::fs::write(dir.join("empty"), "\n").unwrap();
+        for channel in &[
+            format!("kafka:test@127.0.0.1:9093?sasl=gssapi&sasl_file={}", path),
+            "kafka:test@127.0.0.1:9093?sasl=plain".to_string(),
+            format!(
+                "kafka:test@127.0.0.1:9093?sasl=plain&sasl_file={}",
+                dir.join("empty").to_str().unwrap()
+            ),
+        ] {
+            assert!(KafkaOutputConfig::from_uri(&Uri::parse(channel).unwrap()).is_err());
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn headers_from_envelope() {
+        let cfg = KafkaOutputConfig::from_uri(
+            &Uri::parse("kafka:test@127.0.0.1:9092?headers=true").unwrap(),
+        )
+        .unwrap();
+        assert!(cfg.headers);
+
+        let item = Item::from(
+            r#"{"custom":{"nodeId":"web-0","ns":"default","nodeName":"node-1","container":"web","serviceName":"web-svc"},"message":"hello"}"#,
+        );
+        let headers = record_headers(&Envelope::new(&item));
+        let headers = (0..headers.count())
+            .map(|i| {
+                let header = headers.get(i);
+                (
+                    header.key.to_string(),
+                    String::from_utf8_lossy(header.value.unwrap()).to_string(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            headers,
+            vec![
+                ("ns".to_string(), "default".to_string()),
+                ("pod".to_string(), "web-0".to_string()),
+                ("container".to_string(), "web".to_string()),
+                ("service_name".to_string(), "web-svc".to_string()),
+                ("node".to_string(), "node-1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn idle_topics_are_evicted() {
+        let cfg = KafkaOutputConfig::from_uri(
+            &Uri::parse("kafka:logs-{ns}@127.0.0.1:9092?spool_dir=/nonexistent/spool").unwrap(),
+        )
+        .unwrap();
+        let kp = cfg.producer().unwrap();
+        let mut ko = KafkaOuput::new(cfg, kp);
+        ko.idle = Duration::from_millis(50);
+        ko.not_exist_create("logs-a").unwrap();
+        let state = ko.topics["logs-a"].state.clone();
+        thread::sleep(Duration::from_millis(60));
+        ko.not_exist_create("logs-b").unwrap();
+
+        ko.evict_idle();
+        assert!(state.closed.load(Ordering::SeqCst));
+        assert_eq!(ko.topics.keys().collect::<Vec<_>>(), vec!["logs-b"]);
+    }
+
+    #[test]
+    fn leftover_spools_are_replayed() {
+        let dir = std::env::temp_dir().join("harvest_kafka_leftover");
+        let _ = std::fs::remove_dir_all(&dir);
+        let channel = format!(
+            "kafka:logs-{{ns}}@127.0.0.1:9092?spool_dir={}",
+            dir.to_str().unwrap()
+        );
+        let cfg = KafkaOutputConfig::from_uri(&Uri::parse(&channel).unwrap()).unwrap();
+        assert_eq!(cfg.channel, "kafka:logs-{ns}@127.0.0.1:9092");
+
+        // left by a previous run, the file name does not tell the topic
+        let mut spool = Spool::new(&cfg.spool_dir, &cfg.channel, "logs-a", 1024);
+        spool
+            .push(&[serde_json::to_string(&("logs-a", "", "hello")).unwrap()])
+            .unwrap();
+
+        let kp = cfg.producer().unwrap();
+        let mut ko = KafkaOuput::new(cfg, kp);
+        ko.replay_leftover();
+        assert_eq!(ko.topics.keys().collect::<Vec<_>>(), vec!["logs-a"]);
+    }
+
+    #[test]
+    fn render_topic_works() {
+        let item = Item::from(
+            r#"{"custom":{"nodeId":"web-0","ns":"default","container":"web","serviceName":"web svc"},"message":"hello"}"#,
+        );
+        let envelope = Envelope::new(&item);
+        assert_eq!(
+            render_topic("logs-{ns}-{service_name}", &envelope).unwrap(),
+            "logs-default-web_svc"
+        );
+        assert_eq!(
+            render_topic("{pod}.{container}", &envelope).unwrap(),
+            "web-0.web"
+        );
+        assert_eq!(render_topic("logs", &envelope).unwrap(), "logs");
+        assert_eq!(
+            render_topic("logs-{ns}", &Envelope::new(&Item::from("plain"))).unwrap(),
+            "logs-unknown"
+        );
     }
 }
 
@@ -1,34 +1,111 @@
-use common::{Item, Result};
-use kafka_output::KafkaOuput;
+use common::{Ack, Item, Result};
 use once_cell::sync::Lazy;
 
 use std::collections::HashMap;
+use std::fmt;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
+mod backoff;
+mod batch;
+mod elasticsearch_output;
+mod envelope;
+mod fanout;
+mod file_output;
+mod forward_output;
+mod http_output;
 mod kafka_output;
+mod loki_output;
+mod otlp_output;
+mod proto;
+mod redis_output;
+mod spool;
+#[cfg(test)]
+mod stand_in;
+mod stdout_output;
+mod syslog_output;
+mod uri;
 
+pub use envelope::Envelope;
+pub use fanout::fan_out;
+pub use uri::Uri;
 pub use OUTPUTS as OTS;
 
+// builds the output of a channel from its parsed uri
+pub type OutputFactory = fn(&Uri) -> Result<Box<dyn IOutput>>;
+
+static FACTORIES: Lazy<Mutex<HashMap<String, OutputFactory>>> = Lazy::new(|| {
+    let mut factories = HashMap::<String, OutputFactory>::new();
+    factories.insert("kafka".to_string(), kafka_output::factory);
+    factories.insert("es".to_string(), elasticsearch_output::factory);
+    factories.insert("elasticsearch".to_string(), elasticsearch_output::factory);
+    factories.insert("opensearch".to_string(), elasticsearch_output::factory);
+    factories.insert("file".to_string(), file_output::factory);
+    factories.insert("forward".to_string(), forward_output::factory);
+    factories.insert("loki".to_string(), loki_output::factory);
+    factories.insert("otlp".to_string(), otlp_output::factory);
+    factories.insert("redis".to_string(), redis_output::factory);
+    factories.insert("http".to_string(), http_output::factory);
+    factories.insert("stdout".to_string(), stdout_output::factory);
+    factories.insert("https".to_string(), http_output::factory);
+    factories.insert("syslog".to_string(), syslog_output::factory);
+    factories.insert("syslog+udp".to_string(), syslog_output::factory);
+    factories.insert("syslog+tcp".to_string(), syslog_output::factory);
+    Mutex::new(factories)
+});
+
+// an output queue that cannot take more records, the caller backs off and retries
+#[derive(Debug)]
+pub struct QueueFull;
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "output queue is full")
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
 pub static OUTPUTS: Lazy<Arc<Mutex<Outputs>>> = Lazy::new(|| {
     let outputs = Arc::new(Mutex::new(Outputs::new()));
     if let Ok(mut ots) = outputs.lock() {
         ots.registry_output("fake_output", Output::new(FakeOutput));
         ots.registry_output("counter_output", Output::new(Counter(AtomicUsize::new(0))));
+        ots.registry_output(
+            "stdout",
+            Output::new(stdout_output::StdoutOutput::new(
+                stdout_output::Format::Json,
+            )),
+        );
     }
     outputs
 });
 
-pub fn registry_output(channel: &str) {
-    if !channel.starts_with("kafka") {
-        return;
+pub fn registry_output_factory(scheme: &str, factory: OutputFactory) {
+    if let Ok(mut factories) = FACTORIES.lock() {
+        factories.insert(scheme.to_ascii_lowercase(), factory);
     }
-    if let Ok(mut ots) = OUTPUTS.lock() {
+}
+
+pub fn registry_output(channel: &str) -> Result<()> {
+    if let Ok(ots) = OUTPUTS.lock() {
         if ots.contains_output(channel) {
-            return;
+            return Ok(());
         }
-        ots.registry_output(channel, Output::new(KafkaOuput::new()));
     }
+    let uri = Uri::parse(channel)?;
+    let factory = match FACTORIES.lock() {
+        Ok(factories) => match factories.get(&uri.scheme) {
+            Some(factory) => *factory,
+            None => return Err(format!("output scheme `{}` not supported", uri.scheme).into()),
+        },
+        Err(e) => return Err(format!("{:?}", e).into()),
+    };
+    let output = factory(&uri)?;
+    if let Ok(mut ots) = OUTPUTS.lock() {
+        ots.registry_boxed_output(channel, output);
+    }
+    Ok(())
 }
 
 pub struct Outputs {
@@ -57,6 +134,13 @@ impl Outputs {
             .insert(channel.to_string(), Box::new(t));
     }
 
+    pub fn registry_boxed_output(&mut self, channel: &str, output: Box<dyn IOutput>) {
+        if self.output_listener.contains_key(channel) {
+            return;
+        }
+        self.output_listener.insert(channel.to_string(), output);
+    }
+
     pub fn output(&mut self, channel: &str, line: &str) {
         if !self.output_listener.contains_key(channel) {
             if line.len() == 0 {
@@ -76,10 +160,31 @@ impl Outputs {
             _ => {}
         }
     }
+
+    // like output, `ack` is called once the channel delivered the line,
+    // a `QueueFull` error means the line was not taken and should be retried
+    pub fn output_with_ack(&mut self, channel: &str, line: &str, ack: Ack) -> Result<()> {
+        if line.is_empty() {
+            ack.ack();
+            return Ok(());
+        }
+        match self.output_listener.get_mut(channel) {
+            Some(o) => o.write_with_ack(channel, Item::from(line), ack),
+            // never acked, the caller loses the line
+            None => Err(format!("output not found `{:?}`", channel).into()),
+        }
+    }
 }
 
 pub trait IOutput: Send + Sync + 'static {
     fn write(&mut self, channel: &str, item: Item) -> Result<()>;
+
+    // outputs buffering records override this and ack after the sink accepted them
+    fn write_with_ack(&mut self, channel: &str, item: Item, ack: Ack) -> Result<()> {
+        self.write(channel, item)?;
+        ack.ack();
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -100,6 +205,10 @@ impl<T: IOutput> IOutput for Output<T> {
     fn write(&mut self, channel: &str, item: Item) -> Result<()> {
         self.o.write(channel, item)
     }
+
+    fn write_with_ack(&mut self, channel: &str, item: Item, ack: Ack) -> Result<()> {
+        self.o.write_with_ack(channel, item, ack)
+    }
 }
 
 pub fn sync_via_output(line: &str, channel: &str, output: Arc<Mutex<dyn IOutput>>) -> Result<()> {
@@ -179,6 +288,50 @@ mod tests {
         outputs.output("fake_output", "123")
     }
 
+    #[test]
+    fn it_works_with_ack() {
+        let acked = Arc::new(AtomicUsize::new(0));
+        let mut outputs = Outputs::new();
+        outputs.registry_output("fake_output", Output::new(FakeOutput));
+
+        for line in &["123", ""] {
+            let acked = acked.clone();
+            let ack = Ack::new(move || {
+                acked.fetch_add(1, Ordering::SeqCst);
+            });
+            outputs.output_with_ack("fake_output", line, ack).unwrap();
+        }
+        // unknown channel fails without acking
+        let acked_unknown = acked.clone();
+        assert!(outputs
+            .output_with_ack(
+                "unknown_output",
+                "123",
+                Ack::new(move || {
+                    acked_unknown.fetch_add(1, Ordering::SeqCst);
+                }),
+            )
+            .is_err());
+        assert_eq!(acked.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn it_registry_output_by_scheme() {
+        fn fake_factory(_: &Uri) -> Result<Box<dyn IOutput>> {
+            Ok(Box::new(Output::new(FakeOutput)))
+        }
+        registry_output_factory("fake", fake_factory);
+
+        assert!(registry_output("fake://abc").is_ok());
+        assert!(OUTPUTS.lock().unwrap().contains_output("fake://abc"));
+        // registered by name, not parsed again
+        assert!(registry_output("fake_output").is_ok());
+
+        assert!(registry_output("unknown://abc").is_err());
+        assert!(registry_output("kafka:topic").is_err());
+        assert!(registry_output("kafka:@127.0.0.1:9092").is_err());
+    }
+
     #[test]
     fn it_static_outputs() {
         if let Ok(mut ots) = OUTPUTS.try_lock() {
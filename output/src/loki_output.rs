@@ -0,0 +1,405 @@
+use super::batch::{prefixed, proto, Batching, Sink, Worker};
+use super::proto::{put_bytes, put_varint_field};
+use super::{Ack, Envelope, IOutput, Item, Output, QueueFull, Result, Uri};
+use chrono::{DateTime, Utc};
+use ringbuf::{Producer as RProducer, RingBuffer};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::{str::FromStr, thread};
+
+const RING_SIZE: usize = 10240;
+const DEFAULT_BATCH: usize = 1000;
+const PUSH_PATH: &str = "/loki/api/v1/push";
+const LABEL_PREFIX: &str = "label.";
+
+pub(crate) fn factory(uri: &Uri) -> Result<Box<dyn IOutput>> {
+    let cfg = LokiConfig::from_uri(uri)?;
+    Ok(Box::new(Output::new(LokiOutput::new(cfg))))
+}
+
+type Labels = BTreeMap<String, String>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    // snappy compressed logproto.PushRequest
+    Protobuf,
+    Json,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "protobuf" => Ok(Encoding::Protobuf),
+            "json" => Ok(Encoding::Json),
+            _ => Err(format!("unknown loki encoding `{}`", s)),
+        }
+    }
+}
+
+fn valid_label_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[derive(Clone, Debug)]
+struct LokiConfig {
+    url: String,
+    encoding: Encoding,
+    tenant: Option<String>,
+    auth: Option<String>,
+    labels: Labels,
+    batching: Batching,
+}
+
+impl LokiConfig {
+    // channel = loki://loki:3100?encoding=protobuf&tenant=team-a&label.cluster=prod&batch=1000&linger_ms=1000
+    // the push path defaults to /loki/api/v1/push, tls=true, user/password for basic auth,
+    // label.<name> adds a static label to every stream
+    fn from_uri(uri: &Uri) -> Result<Self> {
+        if uri.target.is_empty() || uri.target.starts_with('/') {
+            return Err(format!("loki output `{}` has no host", uri.target).into());
+        }
+        let proto = proto(uri)?;
+        let url = match uri.target.contains('/') {
+            true => format!("{}://{}", proto, uri.target),
+            false => format!("{}://{}{}", proto, uri.target, PUSH_PATH),
+        };
+
+        let labels = prefixed(uri, LABEL_PREFIX)?.into_iter().collect::<Labels>();
+        if let Some(name) = labels.keys().find(|name| !valid_label_name(name)) {
+            return Err(format!("loki label name `{}` is invalid", name).into());
+        }
+
+        let auth = match (uri.param("user"), uri.param("password")) {
+            (Some(user), password) => Some(format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", user, password.unwrap_or_default()))
+            )),
+            (None, Some(_)) => return Err("loki output password without user".into()),
+            (None, None) => None,
+        };
+
+        Ok(Self {
+            url,
+            encoding: uri.param_or("encoding", Encoding::Protobuf)?,
+            tenant: uri.param("tenant").map(|t| t.to_string()),
+            auth,
+            labels,
+            batching: Batching::from_uri(uri, DEFAULT_BATCH)?,
+        })
+    }
+
+    // the pod fields of the envelope plus the static labels, empty values are left out
+    fn labels(&self, envelope: &Envelope) -> Labels {
+        let mut labels = self.labels.clone();
+        for (name, value) in &[
+            ("ns", envelope.ns()),
+            ("service_name", envelope.service_name()),
+            ("pod_name", envelope.pod()),
+            ("container", envelope.container()),
+            ("node_name", envelope.node()),
+        ] {
+            if !value.is_empty() {
+                labels.insert(name.to_string(), value.to_string());
+            }
+        }
+        // loki refuses streams without labels
+        if labels.is_empty() {
+            labels.insert("job".to_string(), "harvest".to_string());
+        }
+        labels
+    }
+}
+
+struct Entry {
+    labels: Labels,
+    // unix nanoseconds
+    time: i64,
+    line: String,
+}
+
+impl Entry {
+    fn new(labels: Labels, item: &Item) -> Self {
+        let envelope = Envelope::new(item);
+        let line = match item {
+            Item::JSON(_) => envelope.message(),
+            Item::Default(line) => line,
+        };
+        let time = DateTime::parse_from_rfc3339(envelope.time())
+            .map(|t| t.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now());
+        Self {
+            labels,
+            time: time.timestamp() * 1_000_000_000 + time.timestamp_subsec_nanos() as i64,
+            line: line.trim_end().to_string(),
+        }
+    }
+}
+
+pub(crate) struct LokiOutput {
+    cfg: LokiConfig,
+    queue: RProducer<(Entry, Ack)>,
+}
+
+impl LokiOutput {
+    fn new(cfg: LokiConfig) -> Self {
+        let (queue, mut c) = RingBuffer::new(RING_SIZE).split();
+        let mut worker = Worker::new(Push(cfg.clone()), &cfg.batching);
+        // pushes block on loki, the loop runs on its own thread
+        thread::spawn(move || worker.write_out(&mut c));
+        Self { cfg, queue }
+    }
+}
+
+impl IOutput for LokiOutput {
+    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
+        self.write_with_ack(channel, item, Ack::none())
+    }
+
+    fn write_with_ack(&mut self, _: &str, item: Item, ack: Ack) -> Result<()> {
+        let labels = self.cfg.labels(&Envelope::new(&item));
+        if self.queue.push((Entry::new(labels, &item), ack)).is_err() {
+            return Err(Box::new(QueueFull));
+        }
+        Ok(())
+    }
+}
+
+// label set as loki prints it, {container="web", ns="default"}
+fn label_string(labels: &Labels) -> String {
+    let pairs = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect::<Vec<String>>();
+    format!("{{{}}}", pairs.join(", "))
+}
+
+// entries grouped per label set, oldest first inside a stream
+fn streams(entries: &[Entry]) -> BTreeMap<&Labels, Vec<&Entry>> {
+    let mut streams = BTreeMap::<&Labels, Vec<&Entry>>::new();
+    for entry in entries {
+        streams.entry(&entry.labels).or_default().push(entry);
+    }
+    for stream in streams.values_mut() {
+        stream.sort_by_key(|entry| entry.time);
+    }
+    streams
+}
+
+fn encode_json(entries: &[Entry]) -> Vec<u8> {
+    let streams = streams(entries)
+        .into_iter()
+        .map(|(labels, entries)| {
+            let stream = labels
+                .iter()
+                .map(|(name, value)| (name.clone(), json!(value)))
+                .collect::<Map<String, Value>>();
+            let values = entries
+                .iter()
+                .map(|entry| json!([entry.time.to_string(), entry.line]))
+                .collect::<Vec<Value>>();
+            json!({"stream": stream, "values": values})
+        })
+        .collect::<Vec<Value>>();
+    json!({ "streams": streams }).to_string().into_bytes()
+}
+
+// logproto.PushRequest, written by hand for the four messages it needs:
+// PushRequest { repeated Stream streams = 1 }
+// Stream { string labels = 1; repeated Entry entries = 2 }
+// Entry { google.protobuf.Timestamp timestamp = 1; string line = 2 }
+// Timestamp { int64 seconds = 1; int32 nanos = 2 }
+fn encode_protobuf(entries: &[Entry]) -> Vec<u8> {
+    let mut request = Vec::new();
+    for (labels, entries) in streams(entries) {
+        let mut stream = Vec::new();
+        put_bytes(&mut stream, 1, label_string(labels).as_bytes());
+        for entry in entries {
+            let mut timestamp = Vec::new();
+            put_varint_field(
+                &mut timestamp,
+                1,
+                entry.time.div_euclid(1_000_000_000) as u64,
+            );
+            put_varint_field(
+                &mut timestamp,
+                2,
+                entry.time.rem_euclid(1_000_000_000) as u64,
+            );
+            let mut message = Vec::new();
+            put_bytes(&mut message, 1, &timestamp);
+            put_bytes(&mut message, 2, entry.line.as_bytes());
+            put_bytes(&mut stream, 2, &message);
+        }
+        put_bytes(&mut request, 1, &stream);
+    }
+    request
+}
+
+// out of order, too old or over the limits, loki never takes a refused batch
+struct Push(LokiConfig);
+
+impl Sink for Push {
+    type Record = Entry;
+
+    const NAME: &'static str = "loki output";
+
+    fn url(&self) -> &str {
+        &self.0.url
+    }
+
+    fn size(entry: &Entry) -> usize {
+        entry.line.len()
+    }
+
+    fn encode(&self, entries: &[Entry]) -> Result<Vec<u8>> {
+        match self.0.encoding {
+            Encoding::Json => Ok(encode_json(entries)),
+            Encoding::Protobuf => {
+                Ok(snap::raw::Encoder::new().compress_vec(&encode_protobuf(entries))?)
+            }
+        }
+    }
+
+    fn request(&self, agent: &ureq::Agent) -> ureq::Request {
+        let mut req = match self.0.encoding {
+            Encoding::Json => agent
+                .post(&self.0.url)
+                .set("Content-Type", "application/json"),
+            Encoding::Protobuf => agent
+                .post(&self.0.url)
+                .set("Content-Type", "application/x-protobuf")
+                .set("Content-Encoding", "snappy"),
+        };
+        if let Some(tenant) = &self.0.tenant {
+            req = req.set("X-Scope-OrgID", tenant);
+        }
+        if let Some(auth) = &self.0.auth {
+            req = req.set("Authorization", auth);
+        }
+        req
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_json, encode_protobuf, label_string, Encoding, Entry, LokiConfig, Push};
+    use crate::batch::Worker;
+    use crate::{stand_in, Envelope, Uri};
+    use common::{Ack, Item};
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn entries(cfg: &LokiConfig) -> Vec<Entry> {
+        [
+            r#"{"custom":{"nodeId":"web-0","ns":"default","container":"web","serviceName":"web-svc","nodeName":"node1"},"message":"second\n","time":"2021-03-16T09:05:01.000000002Z"}"#,
+            r#"{"custom":{"nodeId":"web-0","ns":"default","container":"web","serviceName":"web-svc","nodeName":"node1"},"message":"first\n","time":"2021-03-16T09:05:01.000000001Z"}"#,
+            "plain",
+        ]
+        .iter()
+        .map(|line| {
+            let item = Item::from(*line);
+            Entry::new(cfg.labels(&Envelope::new(&item)), &item)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn config_from_uri() {
+        let cfg = LokiConfig::from_uri(
+            &Uri::parse("loki://loki:3100?encoding=json&tenant=team-a&label.cluster=prod&tls=true")
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(cfg.url, "https://loki:3100/loki/api/v1/push");
+        assert_eq!(cfg.encoding, Encoding::Json);
+        assert_eq!(cfg.tenant, Some("team-a".to_string()));
+        assert_eq!(cfg.labels.get("cluster"), Some(&"prod".to_string()));
+
+        let cfg = LokiConfig::from_uri(&Uri::parse("loki://gateway/tenant/push").unwrap()).unwrap();
+        assert_eq!(cfg.url, "http://gateway/tenant/push");
+        assert_eq!(cfg.encoding, Encoding::Protobuf);
+
+        for channel in &[
+            "loki://",
+            "loki://loki:3100?encoding=xml",
+            "loki://loki:3100?label.1st=a",
+            "loki://loki:3100?password=a",
+            "loki://loki:3100?batch=0",
+        ] {
+            assert!(LokiConfig::from_uri(&Uri::parse(channel).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn encode_json_works() {
+        let cfg = LokiConfig::from_uri(&Uri::parse("loki://loki:3100").unwrap()).unwrap();
+        let body = serde_json::from_slice::<Value>(&encode_json(&entries(&cfg))).unwrap();
+        assert_eq!(
+            body["streams"][0],
+            json!({
+                "stream": {"container": "web", "node_name": "node1", "ns": "default", "pod_name": "web-0", "service_name": "web-svc"},
+                "values": [["1615885501000000001", "first"], ["1615885501000000002", "second"]],
+            })
+        );
+        assert_eq!(body["streams"][1]["stream"], json!({"job": "harvest"}));
+    }
+
+    #[test]
+    fn encode_protobuf_works() {
+        let cfg = LokiConfig::from_uri(&Uri::parse("loki://loki:3100").unwrap()).unwrap();
+        let labels = cfg.labels(&Envelope::new(&Item::from("plain")));
+        let entry = Entry {
+            labels,
+            time: 1_000_000_001,
+            line: "hi".to_string(),
+        };
+        assert_eq!(label_string(&entry.labels), r#"{job="harvest"}"#);
+        let mut expected = vec![0x0a, 29, 0x0a, 15];
+        expected.extend_from_slice(br#"{job="harvest"}"#);
+        expected.extend_from_slice(&[0x12, 10, 0x0a, 4, 0x08, 1, 0x10, 1, 0x12, 2, b'h', b'i']);
+        assert_eq!(encode_protobuf(&[entry]), expected);
+    }
+
+    #[test]
+    fn push_works() {
+        let (port, rx) = stand_in::serve(vec![(500, "", ""), (204, "", "")]);
+        let cfg = LokiConfig::from_uri(
+            &Uri::parse(&format!("loki://127.0.0.1:{}?tenant=team-a", port)).unwrap(),
+        )
+        .unwrap();
+        let acked = Arc::new(AtomicUsize::new(0));
+        let entries = entries(&cfg);
+        let expected = encode_protobuf(&entries);
+        let batch = entries
+            .into_iter()
+            .map(|entry| {
+                let acked = acked.clone();
+                let ack = Ack::new(move || {
+                    acked.fetch_add(1, Ordering::SeqCst);
+                });
+                (entry, ack)
+            })
+            .collect();
+        Worker::new(Push(cfg.clone()), &cfg.batching).flush(batch);
+        assert_eq!(acked.load(Ordering::SeqCst), 3);
+
+        for _ in 0..2 {
+            let req = rx.recv().unwrap();
+            assert!(req.head.starts_with("POST /loki/api/v1/push"));
+            assert_eq!(req.header("x-scope-orgid"), Some("team-a".to_string()));
+            assert_eq!(req.header("content-encoding"), Some("snappy".to_string()));
+            let body = snap::raw::Decoder::new().decompress_vec(&req.body).unwrap();
+            assert_eq!(body, expected);
+        }
+    }
+}
@@ -0,0 +1,436 @@
+use super::batch::{gzip, prefixed, proto, Batching, Sink, Worker, HEADER_PREFIX};
+use super::proto::{put_bytes, put_fixed64_field, put_varint_field};
+use super::{Ack, Envelope, IOutput, Item, Output, QueueFull, Result, Uri};
+use chrono::{DateTime, Utc};
+use ringbuf::{Producer as RProducer, RingBuffer};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::{str::FromStr, thread};
+
+const RING_SIZE: usize = 10240;
+const DEFAULT_BATCH: usize = 500;
+const LOGS_PATH: &str = "/v1/logs";
+const SCOPE_NAME: &str = "harvest";
+// only the first words of a line are looked at for a level
+const SEVERITY_WORDS: usize = 8;
+
+pub(crate) fn factory(uri: &Uri) -> Result<Box<dyn IOutput>> {
+    let cfg = OtlpConfig::from_uri(uri)?;
+    Ok(Box::new(Output::new(OtlpOutput::new(cfg))))
+}
+
+type Attributes = Vec<(&'static str, String)>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Protobuf,
+    Json,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "protobuf" => Ok(Encoding::Protobuf),
+            "json" => Ok(Encoding::Json),
+            _ => Err(format!("unknown otlp encoding `{}`", s)),
+        }
+    }
+}
+
+// severity text and number of the first level word in the line
+fn severity(line: &str) -> Option<(&'static str, u64)> {
+    line.split(|c: char| !c.is_ascii_alphabetic())
+        .filter(|word| !word.is_empty())
+        .take(SEVERITY_WORDS)
+        .find_map(|word| match word.to_ascii_uppercase().as_str() {
+            "TRACE" => Some(("TRACE", 1)),
+            "DEBUG" => Some(("DEBUG", 5)),
+            "INFO" => Some(("INFO", 9)),
+            "WARN" | "WARNING" => Some(("WARN", 13)),
+            "ERROR" | "ERR" => Some(("ERROR", 17)),
+            "FATAL" | "PANIC" | "CRITICAL" => Some(("FATAL", 21)),
+            _ => None,
+        })
+}
+
+#[derive(Clone, Debug)]
+struct OtlpConfig {
+    url: String,
+    encoding: Encoding,
+    headers: Vec<(String, String)>,
+    gzip: bool,
+    batching: Batching,
+}
+
+impl OtlpConfig {
+    // channel = otlp://collector:4318?encoding=protobuf&gzip=true&header.Authorization=Bearer%20abc&batch=500
+    // the logs path defaults to /v1/logs, tls=true, encoding = protobuf | json
+    fn from_uri(uri: &Uri) -> Result<Self> {
+        if uri.target.is_empty() || uri.target.starts_with('/') {
+            return Err(format!("otlp output `{}` has no host", uri.target).into());
+        }
+        let proto = proto(uri)?;
+        let url = match uri.target.contains('/') {
+            true => format!("{}://{}", proto, uri.target),
+            false => format!("{}://{}{}", proto, uri.target, LOGS_PATH),
+        };
+
+        Ok(Self {
+            url,
+            encoding: uri.param_or("encoding", Encoding::Protobuf)?,
+            headers: prefixed(uri, HEADER_PREFIX)?,
+            gzip: uri.param_or("gzip", false)?,
+            batching: Batching::from_uri(uri, DEFAULT_BATCH)?,
+        })
+    }
+}
+
+struct LogRecord {
+    resource: Attributes,
+    attributes: Attributes,
+    // unix nanoseconds, time is 0 when the runtime gave none
+    time: u64,
+    observed: u64,
+    severity: Option<(&'static str, u64)>,
+    body: String,
+}
+
+fn unix_nanos(time: DateTime<Utc>) -> u64 {
+    time.timestamp() as u64 * 1_000_000_000 + time.timestamp_subsec_nanos() as u64
+}
+
+impl LogRecord {
+    fn new(item: &Item) -> Self {
+        let envelope = Envelope::new(item);
+        let body = match item {
+            Item::JSON(_) => envelope.message(),
+            Item::Default(line) => line,
+        }
+        .trim_end()
+        .to_string();
+
+        // pod metadata as k8s resource attributes, empty values are left out
+        let resource = vec![
+            ("k8s.namespace.name", envelope.ns()),
+            ("k8s.pod.name", envelope.pod()),
+            ("k8s.container.name", envelope.container()),
+            ("service.name", envelope.service_name()),
+            ("k8s.node.name", envelope.node()),
+        ]
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| (key, value.to_string()))
+        .collect();
+        let attributes = match envelope.stream() {
+            "" => vec![],
+            stream => vec![("log.iostream", stream.to_string())],
+        };
+
+        Self {
+            resource,
+            attributes,
+            time: DateTime::parse_from_rfc3339(envelope.time())
+                .map(|t| unix_nanos(t.with_timezone(&Utc)))
+                .unwrap_or(0),
+            observed: unix_nanos(Utc::now()),
+            severity: severity(&body),
+            body,
+        }
+    }
+}
+
+// records grouped per resource, in arrival order inside a resource
+fn resources(records: &[LogRecord]) -> BTreeMap<&Attributes, Vec<&LogRecord>> {
+    let mut resources = BTreeMap::<&Attributes, Vec<&LogRecord>>::new();
+    for record in records {
+        resources.entry(&record.resource).or_default().push(record);
+    }
+    resources
+}
+
+fn json_attributes(attributes: &[(&str, String)]) -> Value {
+    attributes
+        .iter()
+        .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+        .collect()
+}
+
+fn encode_json(records: &[LogRecord]) -> Vec<u8> {
+    let resource_logs = resources(records)
+        .into_iter()
+        .map(|(resource, records)| {
+            let log_records = records
+                .iter()
+                .map(|record| {
+                    let mut log_record = json!({
+                        "observedTimeUnixNano": record.observed.to_string(),
+                        "body": {"stringValue": record.body},
+                        "attributes": json_attributes(&record.attributes),
+                    });
+                    if record.time > 0 {
+                        log_record["timeUnixNano"] = json!(record.time.to_string());
+                    }
+                    if let Some((text, number)) = record.severity {
+                        log_record["severityText"] = json!(text);
+                        log_record["severityNumber"] = json!(number);
+                    }
+                    log_record
+                })
+                .collect::<Vec<Value>>();
+            json!({
+                "resource": {"attributes": json_attributes(resource)},
+                "scopeLogs": [{"scope": {"name": SCOPE_NAME}, "logRecords": log_records}],
+            })
+        })
+        .collect::<Vec<Value>>();
+    json!({ "resourceLogs": resource_logs })
+        .to_string()
+        .into_bytes()
+}
+
+// KeyValue { string key = 1; AnyValue value = 2 }, AnyValue { string string_value = 1 }
+fn put_attribute(buf: &mut Vec<u8>, field: u64, key: &str, value: &str) {
+    let mut any = Vec::new();
+    put_bytes(&mut any, 1, value.as_bytes());
+    let mut kv = Vec::new();
+    put_bytes(&mut kv, 1, key.as_bytes());
+    put_bytes(&mut kv, 2, &any);
+    put_bytes(buf, field, &kv);
+}
+
+// ExportLogsServiceRequest { repeated ResourceLogs resource_logs = 1 }
+// ResourceLogs { Resource resource = 1; repeated ScopeLogs scope_logs = 2 }
+// Resource { repeated KeyValue attributes = 1 }
+// ScopeLogs { InstrumentationScope scope = 1; repeated LogRecord log_records = 2 }
+// InstrumentationScope { string name = 1 }
+// LogRecord { fixed64 time_unix_nano = 1; SeverityNumber severity_number = 2;
+//   string severity_text = 3; AnyValue body = 5; repeated KeyValue attributes = 6;
+//   fixed64 observed_time_unix_nano = 11 }
+fn encode_protobuf(records: &[LogRecord]) -> Vec<u8> {
+    let mut request = Vec::new();
+    for (resource, records) in resources(records) {
+        let mut attributes = Vec::new();
+        for (key, value) in resource {
+            put_attribute(&mut attributes, 1, key, value);
+        }
+        let mut scope = Vec::new();
+        put_bytes(&mut scope, 1, SCOPE_NAME.as_bytes());
+        let mut scope_logs = Vec::new();
+        put_bytes(&mut scope_logs, 1, &scope);
+        for record in records {
+            let mut log_record = Vec::new();
+            put_fixed64_field(&mut log_record, 1, record.time);
+            if let Some((text, number)) = record.severity {
+                put_varint_field(&mut log_record, 2, number);
+                put_bytes(&mut log_record, 3, text.as_bytes());
+            }
+            let mut body = Vec::new();
+            put_bytes(&mut body, 1, record.body.as_bytes());
+            put_bytes(&mut log_record, 5, &body);
+            for (key, value) in &record.attributes {
+                put_attribute(&mut log_record, 6, key, value);
+            }
+            put_fixed64_field(&mut log_record, 11, record.observed);
+            put_bytes(&mut scope_logs, 2, &log_record);
+        }
+        let mut resource_logs = Vec::new();
+        put_bytes(&mut resource_logs, 1, &attributes);
+        put_bytes(&mut resource_logs, 2, &scope_logs);
+        put_bytes(&mut request, 1, &resource_logs);
+    }
+    request
+}
+
+pub(crate) struct OtlpOutput {
+    queue: RProducer<(LogRecord, Ack)>,
+}
+
+impl OtlpOutput {
+    fn new(cfg: OtlpConfig) -> Self {
+        let (queue, mut c) = RingBuffer::new(RING_SIZE).split();
+        let mut worker = Worker::new(Exporter(cfg.clone()), &cfg.batching);
+        // exports block on the collector, the loop runs on its own thread
+        thread::spawn(move || worker.write_out(&mut c));
+        Self { queue }
+    }
+}
+
+impl IOutput for OtlpOutput {
+    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
+        self.write_with_ack(channel, item, Ack::none())
+    }
+
+    fn write_with_ack(&mut self, _: &str, item: Item, ack: Ack) -> Result<()> {
+        if self.queue.push((LogRecord::new(&item), ack)).is_err() {
+            return Err(Box::new(QueueFull));
+        }
+        Ok(())
+    }
+}
+
+// exports batches, retrying the statuses otlp/http marks as retryable
+struct Exporter(OtlpConfig);
+
+impl Sink for Exporter {
+    type Record = LogRecord;
+
+    const NAME: &'static str = "otlp output";
+
+    fn url(&self) -> &str {
+        &self.0.url
+    }
+
+    fn size(record: &LogRecord) -> usize {
+        record.body.len()
+    }
+
+    fn encode(&self, records: &[LogRecord]) -> Result<Vec<u8>> {
+        let body = match self.0.encoding {
+            Encoding::Protobuf => encode_protobuf(records),
+            Encoding::Json => encode_json(records),
+        };
+        match self.0.gzip {
+            true => gzip(&body),
+            false => Ok(body),
+        }
+    }
+
+    fn request(&self, agent: &ureq::Agent) -> ureq::Request {
+        let content_type = match self.0.encoding {
+            Encoding::Protobuf => "application/x-protobuf",
+            Encoding::Json => "application/json",
+        };
+        let mut req = agent.post(&self.0.url).set("Content-Type", content_type);
+        if self.0.gzip {
+            req = req.set("Content-Encoding", "gzip");
+        }
+        for (name, value) in &self.0.headers {
+            req = req.set(name, value);
+        }
+        req
+    }
+
+    fn retryable(&self, code: u16) -> bool {
+        [429, 502, 503, 504].contains(&code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        encode_json, encode_protobuf, severity, Encoding, Exporter, LogRecord, OtlpConfig,
+    };
+    use crate::batch::Worker;
+    use crate::{stand_in, Uri};
+    use common::{Ack, Item};
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const RECORD: &str = r#"{"custom":{"nodeId":"web-0","ns":"default","container":"web","serviceName":"web-svc","nodeName":"node1"},"message":"2021-03-16 09:05:01 [WARN] disk almost full\n","stream":"stderr","time":"2021-03-16T09:05:01.000000001Z"}"#;
+
+    #[test]
+    fn config_from_uri() {
+        let cfg = OtlpConfig::from_uri(
+            &Uri::parse("otlp://collector:4318?encoding=json&tls=true&header.X-Token=abc").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(cfg.url, "https://collector:4318/v1/logs");
+        assert_eq!(cfg.encoding, Encoding::Json);
+        assert_eq!(
+            cfg.headers,
+            vec![("X-Token".to_string(), "abc".to_string())]
+        );
+
+        for channel in &[
+            "otlp://",
+            "otlp://collector:4318?encoding=xml",
+            "otlp://collector:4318?batch=0",
+            "otlp://collector:4318?header.=abc",
+        ] {
+            assert!(OtlpConfig::from_uri(&Uri::parse(channel).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn severity_works() {
+        assert_eq!(severity("[WARN] disk almost full"), Some(("WARN", 13)));
+        assert_eq!(
+            severity(r#"{"level":"error","msg":"boom"}"#),
+            Some(("ERROR", 17))
+        );
+        assert_eq!(severity("ts=1 level=debug msg=hi"), Some(("DEBUG", 5)));
+        assert_eq!(severity("GET /index 200"), None);
+        assert_eq!(severity("a b c d e f g h error"), None);
+    }
+
+    #[test]
+    fn encode_json_works() {
+        let record = LogRecord::new(&Item::from(RECORD));
+        let body = serde_json::from_slice::<Value>(&encode_json(&[record])).unwrap();
+        let resource_logs = &body["resourceLogs"][0];
+        assert_eq!(
+            resource_logs["resource"]["attributes"],
+            json!([
+                {"key": "k8s.namespace.name", "value": {"stringValue": "default"}},
+                {"key": "k8s.pod.name", "value": {"stringValue": "web-0"}},
+                {"key": "k8s.container.name", "value": {"stringValue": "web"}},
+                {"key": "service.name", "value": {"stringValue": "web-svc"}},
+                {"key": "k8s.node.name", "value": {"stringValue": "node1"}},
+            ])
+        );
+        let log_record = &resource_logs["scopeLogs"][0]["logRecords"][0];
+        assert_eq!(log_record["timeUnixNano"], json!("1615885501000000001"));
+        assert_eq!(log_record["severityText"], json!("WARN"));
+        assert_eq!(log_record["severityNumber"], json!(13));
+        assert_eq!(
+            log_record["body"]["stringValue"],
+            json!("2021-03-16 09:05:01 [WARN] disk almost full")
+        );
+        assert_eq!(
+            log_record["attributes"],
+            json!([{"key": "log.iostream", "value": {"stringValue": "stderr"}}])
+        );
+    }
+
+    #[test]
+    fn encode_protobuf_works() {
+        let mut record = LogRecord::new(&Item::from("hi"));
+        record.observed = 1;
+        let mut expected = vec![0x0a, 32, 0x0a, 0, 0x12, 28, 0x0a, 9, 0x0a, 7];
+        expected.extend_from_slice(b"harvest");
+        expected.extend_from_slice(&[0x12, 15, 0x2a, 4, 0x0a, 2, b'h', b'i', 0x59]);
+        expected.extend_from_slice(&1u64.to_le_bytes());
+        assert_eq!(encode_protobuf(&[record]), expected);
+    }
+
+    #[test]
+    fn exporter_retries() {
+        let (port, rx) = stand_in::serve(vec![(503, "Retry-After: 0\r\n", ""), (200, "", "{}")]);
+        let cfg = OtlpConfig::from_uri(
+            &Uri::parse(&format!("otlp://127.0.0.1:{}?header.X-Token=abc", port)).unwrap(),
+        )
+        .unwrap();
+        let acked = Arc::new(AtomicUsize::new(0));
+        let acked_clone = acked.clone();
+        let ack = Ack::new(move || {
+            acked_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        Worker::new(Exporter(cfg.clone()), &cfg.batching)
+            .flush(vec![(LogRecord::new(&Item::from(RECORD)), ack)]);
+        assert_eq!(acked.load(Ordering::SeqCst), 1);
+
+        for _ in 0..2 {
+            let req = rx.recv().unwrap();
+            assert!(req.head.starts_with("POST /v1/logs"));
+            assert_eq!(req.header("x-token"), Some("abc".to_string()));
+            assert_eq!(
+                req.header("content-type"),
+                Some("application/x-protobuf".to_string())
+            );
+            assert!(!req.body.is_empty());
+        }
+    }
+}
@@ -0,0 +1,48 @@
+// the few protobuf wire format pieces the hand written messages need
+
+pub(crate) fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+// zero is the default and is not written
+pub(crate) fn put_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    if value != 0 {
+        put_varint(buf, field << 3);
+        put_varint(buf, value);
+    }
+}
+
+pub(crate) fn put_fixed64_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    if value != 0 {
+        put_varint(buf, field << 3 | 1);
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+pub(crate) fn put_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buf, field << 3 | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{put_bytes, put_fixed64_field, put_varint_field};
+
+    #[test]
+    fn wire_format_works() {
+        let mut buf = Vec::new();
+        put_varint_field(&mut buf, 1, 300);
+        put_varint_field(&mut buf, 2, 0);
+        put_fixed64_field(&mut buf, 3, 1);
+        put_bytes(&mut buf, 4, b"hi");
+        assert_eq!(
+            buf,
+            vec![0x08, 0xac, 0x02, 0x19, 1, 0, 0, 0, 0, 0, 0, 0, 0x22, 2, b'h', b'i']
+        );
+    }
+}
@@ -0,0 +1,478 @@
+use super::backoff::Backoff;
+use super::{Ack, Envelope, IOutput, Item, Output, QueueFull, Result, Uri};
+use redis::{
+    Client, ConnectionAddr, ConnectionInfo, ErrorKind, RedisConnectionInfo, RedisError, RedisResult,
+};
+use ringbuf::{Consumer as RConsumer, Producer as RProducer, RingBuffer};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const RING_SIZE: usize = 10240;
+const DEFAULT_PORT: u16 = 6379;
+const DEFAULT_BATCH: usize = 100;
+const DEFAULT_LINGER_MS: u64 = 100;
+const DEFAULT_TIMEOUT_MS: u64 = 5000;
+
+pub(crate) fn factory(uri: &Uri) -> Result<Box<dyn IOutput>> {
+    let cfg = RedisConfig::from_uri(uri)?;
+    Ok(Box::new(Output::new(RedisOutput::new(cfg)?)))
+}
+
+#[derive(Clone, Debug)]
+struct RedisConfig {
+    info: ConnectionInfo,
+    key: String,
+    maxlen: usize,
+    batch: usize,
+    linger: Duration,
+    timeout: Duration,
+}
+
+impl RedisConfig {
+    // channel = redis://10.200.100.200:6379/logs:{ns}:{service_name}?maxlen=100000&batch=100&linger_ms=100
+    // key fields: {ns}, {pod}, {container}, {service_name}, {node}
+    // maxlen trims each stream to about that many entries (MAXLEN ~), 0 keeps everything,
+    // user, password and db select the connection
+    fn from_uri(uri: &Uri) -> Result<Self> {
+        let (addr, key) = match uri.target.find('/') {
+            Some(i) => (&uri.target[..i], &uri.target[i + 1..]),
+            None => (uri.target.as_str(), ""),
+        };
+        let (host, port) = match addr.rfind(':') {
+            Some(i) => match addr[i + 1..].parse::<u16>() {
+                Ok(port) => (&addr[..i], port),
+                Err(_) => return Err(format!("redis output port in `{}` is invalid", addr).into()),
+            },
+            None => (addr, DEFAULT_PORT),
+        };
+        if host.is_empty() || key.is_empty() {
+            return Err(format!("redis output `{}` expects host:port/key", uri.target).into());
+        }
+        Envelope::new(&Item::Default(String::new())).render(key, |c| c)?;
+
+        let batch = uri.param_or("batch", DEFAULT_BATCH)?;
+        if batch == 0 {
+            return Err("redis output batch must be greater than 0".into());
+        }
+        Ok(Self {
+            info: ConnectionInfo {
+                addr: ConnectionAddr::Tcp(host.to_string(), port),
+                redis: RedisConnectionInfo {
+                    db: uri.param_or("db", 0)?,
+                    username: uri.param("user").map(|u| u.to_string()),
+                    password: uri.param("password").map(|p| p.to_string()),
+                },
+            },
+            key: key.to_string(),
+            maxlen: uri.param_or("maxlen", 0)?,
+            batch,
+            linger: Duration::from_millis(uri.param_or("linger_ms", DEFAULT_LINGER_MS)?),
+            timeout: Duration::from_millis(uri.param_or("timeout_ms", DEFAULT_TIMEOUT_MS)?),
+        })
+    }
+}
+
+struct Entry {
+    key: String,
+    fields: Vec<(&'static str, String)>,
+    ack: Ack,
+}
+
+impl Entry {
+    // the envelope fields that have a value, the message is always there
+    fn new(key: String, item: &Item, ack: Ack) -> Self {
+        let envelope = Envelope::new(item);
+        let message = match item {
+            Item::JSON(_) => envelope.message(),
+            Item::Default(line) => line,
+        };
+        let fields = vec![
+            ("ns", envelope.ns()),
+            ("pod", envelope.pod()),
+            ("container", envelope.container()),
+            ("service_name", envelope.service_name()),
+            ("node", envelope.node()),
+            ("stream", envelope.stream()),
+            ("time", envelope.time()),
+        ]
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(name, value)| (name, value.to_string()))
+        .chain(std::iter::once(("message", message.trim_end().to_string())))
+        .collect();
+        Self { key, fields, ack }
+    }
+}
+
+pub(crate) struct RedisOutput {
+    cfg: RedisConfig,
+    queue: RProducer<Entry>,
+}
+
+impl RedisOutput {
+    fn new(cfg: RedisConfig) -> Result<Self> {
+        let client = Client::open(cfg.info.clone())?;
+        let mut stream = Stream::new(cfg.clone(), client);
+        // a refused password or db stays refused, registering fails instead of
+        // retrying it forever. a server that is down is retried by the stream
+        match stream.connect() {
+            Ok(_) => {}
+            Err(e) if retryable(&e) => {
+                eprintln!("redis output {:?} error: {}", cfg.info.addr, e)
+            }
+            Err(e) => return Err(format!("redis output {:?} error: {}", cfg.info.addr, e).into()),
+        }
+        let (queue, mut c) = RingBuffer::new(RING_SIZE).split();
+        // pipelines block on the server, the loop runs on its own thread
+        thread::spawn(move || stream.write_out(&mut c));
+        Ok(Self { cfg, queue })
+    }
+}
+
+impl IOutput for RedisOutput {
+    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
+        self.write_with_ack(channel, item, Ack::none())
+    }
+
+    fn write_with_ack(&mut self, _: &str, item: Item, ack: Ack) -> Result<()> {
+        let key = Envelope::new(&item).render(&self.cfg.key, |c| match c {
+            ' ' | '\t' | '\r' | '\n' => '_',
+            _ => c,
+        })?;
+        if self.queue.push(Entry::new(key, &item, ack)).is_err() {
+            return Err(Box::new(QueueFull));
+        }
+        Ok(())
+    }
+}
+
+// one pipeline of XADD per batch, a failed batch is sent again on a new connection
+struct Stream {
+    cfg: RedisConfig,
+    client: Client,
+    conn: Option<redis::Connection>,
+    backoff: Backoff,
+}
+
+impl Stream {
+    fn new(cfg: RedisConfig, client: Client) -> Self {
+        Self {
+            client,
+            conn: None,
+            backoff: Backoff::new(),
+            cfg,
+        }
+    }
+
+    fn write_out(&mut self, cons: &mut RConsumer<Entry>) {
+        let mut now = Instant::now();
+        let mut batch = Vec::with_capacity(self.cfg.batch);
+        loop {
+            match cons.pop() {
+                Some(entry) => {
+                    if batch.is_empty() {
+                        now = Instant::now();
+                    }
+                    batch.push(entry);
+                }
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+            if batch.len() >= self.cfg.batch
+                || (!batch.is_empty() && now.elapsed() >= self.cfg.linger)
+            {
+                self.flush(std::mem::take(&mut batch));
+            }
+        }
+    }
+
+    fn flush(&mut self, entries: Vec<Entry>) {
+        let mut pipe = redis::pipe();
+        for entry in &entries {
+            let cmd = pipe.cmd("XADD").arg(&entry.key);
+            if self.cfg.maxlen > 0 {
+                cmd.arg("MAXLEN").arg("~").arg(self.cfg.maxlen);
+            }
+            cmd.arg("*");
+            for (name, value) in &entry.fields {
+                cmd.arg(*name).arg(value);
+            }
+            cmd.ignore();
+        }
+        loop {
+            match self.send(&pipe) {
+                Ok(_) => {
+                    self.backoff.reset();
+                    break;
+                }
+                Err(e) if retryable(&e) => {
+                    eprintln!("redis output {:?} error: {}", self.cfg.info.addr, e);
+                    self.conn = None;
+                    thread::sleep(self.backoff.fail());
+                }
+                Err(e) => {
+                    // a wrong type or a bad command stays wrong, the batch is dropped
+                    eprintln!(
+                        "redis output {:?} rejected {} entries: {}",
+                        self.cfg.info.addr,
+                        entries.len(),
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+        for entry in entries {
+            entry.ack.ack();
+        }
+    }
+
+    fn connect(&mut self) -> RedisResult<()> {
+        if self.conn.is_none() {
+            let conn = self.client.get_connection_with_timeout(self.cfg.timeout)?;
+            conn.set_read_timeout(Some(self.cfg.timeout))?;
+            conn.set_write_timeout(Some(self.cfg.timeout))?;
+            self.conn = Some(conn);
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, pipe: &redis::Pipeline) -> RedisResult<()> {
+        self.connect()?;
+        pipe.query::<()>(self.conn.as_mut().unwrap())
+    }
+}
+
+// the server is unreachable, loading, failing over or out of memory for now
+fn retryable(e: &RedisError) -> bool {
+    match e.kind() {
+        ErrorKind::IoError
+        | ErrorKind::BusyLoadingError
+        | ErrorKind::TryAgain
+        | ErrorKind::ClusterDown
+        | ErrorKind::MasterDown
+        | ErrorKind::ReadOnly => true,
+        ErrorKind::ExtensionError => e.code() == Some("OOM"),
+        _ => e.is_io_error() || e.is_timeout() || e.is_connection_dropped(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{factory, retryable, Entry, RedisConfig, Stream};
+    use crate::Uri;
+    use common::{Ack, Item};
+    use redis::{Client, ConnectionAddr, ErrorKind, RedisError};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::process::Command;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    // answers every command of the first connection with `first`, then with a stream id,
+    // sends back the commands it got
+    fn fake_redis(first: &'static str) -> (u16, mpsc::Receiver<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for (n, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                    let count = line.trim_end()[1..].parse::<usize>().unwrap();
+                    let mut args = vec![];
+                    for _ in 0..count * 2 {
+                        line.clear();
+                        reader.read_line(&mut line).unwrap();
+                        if !line.starts_with('$') {
+                            args.push(line.trim_end().to_string());
+                        }
+                    }
+                    line.clear();
+                    let reply = if n == 0 { first } else { "$3\r\n1-0\r\n" };
+                    stream.write_all(reply.as_bytes()).unwrap();
+                    tx.send(args).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                }
+            }
+        });
+        (port, rx)
+    }
+
+    #[test]
+    fn config_from_uri() {
+        let cfg = RedisConfig::from_uri(
+            &Uri::parse("redis://10.0.0.1/logs:{ns}:{service_name}?maxlen=1000&db=2").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.info.addr,
+            ConnectionAddr::Tcp("10.0.0.1".to_string(), 6379)
+        );
+        assert_eq!(cfg.info.redis.db, 2);
+        assert_eq!(cfg.key, "logs:{ns}:{service_name}");
+        assert_eq!(cfg.maxlen, 1000);
+
+        for channel in &[
+            "redis://10.0.0.1:6379",
+            "redis://:6379/logs",
+            "redis://10.0.0.1:port/logs",
+            "redis://10.0.0.1/logs:{namespace}",
+            "redis://10.0.0.1/logs?batch=0",
+        ] {
+            assert!(RedisConfig::from_uri(&Uri::parse(channel).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn flush_works() {
+        let (port, rx) = fake_redis("-LOADING Redis is loading the dataset in memory\r\n");
+        let cfg = RedisConfig::from_uri(
+            &Uri::parse(&format!(
+                "redis://127.0.0.1:{}/logs:{{ns}}?maxlen=1000",
+                port
+            ))
+            .unwrap(),
+        )
+        .unwrap();
+
+        let acked = Arc::new(AtomicUsize::new(0));
+        let entries = [
+            r#"{"custom":{"nodeId":"web-0","ns":"default"},"message":"hello\n","stream":"stdout"}"#,
+            "plain",
+        ]
+        .iter()
+        .map(|line| {
+            let acked = acked.clone();
+            Entry::new(
+                "logs:default".to_string(),
+                &Item::from(*line),
+                Ack::new(move || {
+                    acked.fetch_add(1, Ordering::SeqCst);
+                }),
+            )
+        })
+        .collect::<Vec<Entry>>();
+        let client = Client::open(cfg.info.clone()).unwrap();
+        Stream::new(cfg, client).flush(entries);
+        assert_eq!(acked.load(Ordering::SeqCst), 2);
+
+        // the refused pipeline is sent again in full on a new connection
+        let first = rx.recv().unwrap();
+        assert_eq!(first, rx.recv().unwrap());
+        assert_eq!(
+            first,
+            vec![
+                "XADD",
+                "logs:default",
+                "MAXLEN",
+                "~",
+                "1000",
+                "*",
+                "ns",
+                "default",
+                "pod",
+                "web-0",
+                "stream",
+                "stdout",
+                "message",
+                "hello"
+            ]
+        );
+        assert_eq!(
+            rx.recv().unwrap(),
+            vec![
+                "XADD",
+                "logs:default",
+                "MAXLEN",
+                "~",
+                "1000",
+                "*",
+                "message",
+                "plain"
+            ]
+        );
+    }
+
+    #[test]
+    fn auth_failure_is_fatal() {
+        let (port, rx) = fake_redis("-WRONGPASS invalid username-password pair\r\n");
+        let channel = format!("redis://127.0.0.1:{}/logs?password=wrong", port);
+        assert!(factory(&Uri::parse(&channel).unwrap()).is_err());
+        assert_eq!(rx.recv().unwrap(), vec!["AUTH", "wrong"]);
+    }
+
+    #[test]
+    fn retryable_works() {
+        let io = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(retryable(&RedisError::from(io)));
+        assert!(retryable(&RedisError::from((
+            ErrorKind::BusyLoadingError,
+            "loading"
+        ))));
+        assert!(!retryable(&RedisError::from((
+            ErrorKind::ResponseError,
+            "WRONGTYPE"
+        ))));
+        assert!(!retryable(&RedisError::from((
+            ErrorKind::AuthenticationFailed,
+            "Password authentication failed"
+        ))));
+    }
+
+    // needs redis-server on the path, cargo test -p output -- --ignored
+    #[test]
+    #[ignore]
+    fn redis_server_works() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut server = Command::new("redis-server")
+            .args(["--port", &port.to_string(), "--requirepass", "secret"])
+            .args(["--save", "", "--appendonly", "no"])
+            .spawn()
+            .unwrap();
+        for _ in 0..100 {
+            if std::net::TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let channel = format!("redis://127.0.0.1:{}/logs:{{ns}}?password=wrong", port);
+        assert!(factory(&Uri::parse(&channel).unwrap()).is_err());
+
+        let channel = format!(
+            "redis://127.0.0.1:{}/logs:{{ns}}?password=secret&linger_ms=10",
+            port
+        );
+        let mut output = factory(&Uri::parse(&channel).unwrap()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let ack = Ack::new(move || tx.lock().unwrap().send(()).unwrap());
+        output
+            .write_with_ack(
+                &channel,
+                Item::from(r#"{"custom":{"ns":"default"},"message":"hello\n"}"#),
+                ack,
+            )
+            .unwrap();
+        let acked = rx.recv_timeout(Duration::from_secs(5));
+
+        let client = Client::open(format!("redis://:secret@127.0.0.1:{}", port)).unwrap();
+        let len = redis::cmd("XLEN")
+            .arg("logs:default")
+            .query::<usize>(&mut client.get_connection().unwrap());
+        server.kill().unwrap();
+        server.wait().unwrap();
+        assert!(acked.is_ok());
+        assert_eq!(len.unwrap(), 1);
+    }
+}
@@ -0,0 +1,209 @@
+use common::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+// append only on-disk overflow of records a sink could not take, one record per line
+pub(crate) struct Spool {
+    path: PathBuf,
+    bytes: u64,
+    // records before this file offset were replayed, a restart replays them again
+    read: u64,
+    max_bytes: u64,
+}
+
+// stable across runs unlike DefaultHasher, spool names must survive a restart
+fn fnv(s: &str) -> u64 {
+    s.bytes().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl Spool {
+    // one spool per channel and topic, named by their hashes so any topic fits a file name
+    pub(crate) fn new(dir: &str, channel: &str, topic: &str, max_bytes: u64) -> Self {
+        let path =
+            PathBuf::from(dir).join(format!("{:016x}-{:016x}.spool", fnv(channel), fnv(topic)));
+        // records left by a previous run are replayed as well
+        let bytes = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        Self {
+            path,
+            bytes,
+            read: 0,
+            max_bytes,
+        }
+    }
+
+    // the first record of every spool a previous run of `channel` left in `dir`
+    pub(crate) fn leftover(dir: &str, channel: &str) -> Vec<String> {
+        let prefix = format!("{:016x}-", fnv(channel));
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                name.starts_with(&prefix) && name.ends_with(".spool")
+            })
+            .filter_map(|entry| {
+                let file = File::open(entry.path()).ok()?;
+                BufReader::new(file).lines().next()?.ok()
+            })
+            .collect()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.read == self.bytes
+    }
+
+    // records are durable once this returns, a full spool refuses the whole batch
+    pub(crate) fn push(&mut self, records: &[String]) -> Result<()> {
+        let bytes = records.iter().map(|r| r.len() as u64 + 1).sum::<u64>();
+        if self.bytes + bytes > self.max_bytes {
+            return Err(format!("spool {:?} is full", self.path).into());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut w = BufWriter::new(file);
+        for record in records {
+            w.write_all(record.as_bytes())?;
+            w.write_all(b"\n")?;
+        }
+        w.flush()?;
+        w.get_ref().sync_all()?;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    // hands spooled records to `send` in batches of `batch` from the read offset on,
+    // stops at the first failure keeping the offset for the next replay.
+    // the file is removed only once every record went out
+    pub(crate) fn replay<F>(&mut self, batch: usize, mut send: F) -> Result<()>
+    where
+        F: FnMut(&[String]) -> Result<()>,
+    {
+        let mut r = match File::open(&self.path) {
+            Ok(file) => BufReader::new(file),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.bytes = 0;
+                self.read = 0;
+                return Ok(());
+            }
+            Err(e) => return Err(Box::new(e)),
+        };
+        r.seek(SeekFrom::Start(self.read))?;
+
+        let mut chunk = Vec::with_capacity(batch.max(1));
+        let mut size = 0;
+        loop {
+            let mut line = String::new();
+            let n = r.read_line(&mut line)?;
+            if n > 0 {
+                chunk.push(line.trim_end_matches('\n').to_string());
+                size += n as u64;
+            }
+            if !chunk.is_empty() && (chunk.len() >= batch.max(1) || n == 0) {
+                send(&chunk)?;
+                self.read += size;
+                chunk.clear();
+                size = 0;
+            }
+            if n == 0 {
+                break;
+            }
+        }
+        fs::remove_file(&self.path)?;
+        self.bytes = 0;
+        self.read = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Spool;
+    use std::fs;
+
+    #[test]
+    fn spool_works() {
+        let dir = std::env::temp_dir().join("harvest_output_spool");
+        let _ = fs::remove_dir_all(&dir);
+        let mut spool = Spool::new(
+            dir.to_str().unwrap(),
+            "kafka:test@127.0.0.1:9092",
+            "test",
+            64,
+        );
+        assert!(spool.is_empty());
+
+        let records = (0..5).map(|i| format!("r{}", i)).collect::<Vec<String>>();
+        spool.push(&records).unwrap();
+        assert!(!spool.is_empty());
+        assert!(spool.push(&["x".repeat(64)]).is_err());
+
+        // the second batch fails, it stays spooled
+        let mut sent = vec![];
+        let mut calls = 0;
+        assert!(spool
+            .replay(2, |chunk| {
+                calls += 1;
+                if calls == 2 {
+                    return Err("broker down".into());
+                }
+                sent.extend_from_slice(chunk);
+                Ok(())
+            })
+            .is_err());
+        assert_eq!(sent, vec!["r0", "r1"]);
+        // the file is not rewritten, the next replay starts after the sent records
+        assert_eq!(fs::metadata(&spool.path).unwrap().len(), 15);
+        assert!(!spool.is_empty());
+        spool.push(&["r5".to_string()]).unwrap();
+
+        spool
+            .replay(2, |chunk| {
+                sent.extend_from_slice(chunk);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(sent, [&records[..], &["r5".to_string()]].concat());
+        assert!(spool.is_empty());
+        assert!(!spool.path.exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn spool_names_are_hashed() {
+        let dir = std::env::temp_dir().join("harvest_output_spool_names");
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_str().unwrap();
+
+        // topics differing only in characters a file name cannot hold stay apart
+        let long = "logs-".repeat(100);
+        for topic in &["logs.a", "logs_a", long.as_str()] {
+            let mut spool = Spool::new(dir, "kafka:logs-{ns}@127.0.0.1:9092", topic, 1024);
+            spool.push(&[topic.to_string()]).unwrap();
+        }
+        let mut spool = Spool::new(dir, "kafka:other@127.0.0.1:9092", "logs.a", 1024);
+        spool.push(&["other".to_string()]).unwrap();
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 4);
+
+        let mut leftover = Spool::leftover(dir, "kafka:logs-{ns}@127.0.0.1:9092");
+        leftover.sort();
+        assert_eq!(
+            leftover,
+            vec![long.clone(), "logs.a".to_string(), "logs_a".to_string()]
+        );
+        assert!(Spool::leftover("/nonexistent/spool", "kafka:other@127.0.0.1:9092").is_empty());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,68 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+
+// a request as the stand-in received it
+pub(crate) struct Request {
+    pub(crate) head: String,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    pub(crate) fn header(&self, name: &str) -> Option<String> {
+        let prefix = format!("{}:", name.to_ascii_lowercase());
+        self.head.lines().find_map(|line| {
+            line.to_ascii_lowercase()
+                .strip_prefix(&prefix)
+                .map(|_| line[prefix.len()..].trim().to_string())
+        })
+    }
+
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+}
+
+// local http server answering one request per (status, extra headers, body),
+// sends back every request it got
+pub(crate) fn serve(
+    responses: Vec<(u16, &'static str, &'static str)>,
+) -> (u16, mpsc::Receiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for (status, headers, body) in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" || line.is_empty() {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            let mut req = Request { head, body: vec![] };
+            let len = req
+                .header("content-length")
+                .map(|v| v.parse::<usize>().unwrap())
+                .unwrap_or(0);
+            req.body = vec![0; len];
+            reader.read_exact(&mut req.body).unwrap();
+            let _ = tx.send(req);
+            write!(
+                stream,
+                "HTTP/1.1 {} STAND-IN\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                headers,
+                body.len(),
+                body
+            )
+            .unwrap();
+        }
+    });
+    (port, rx)
+}
@@ -0,0 +1,141 @@
+use super::{Envelope, IOutput, Item, Output, Result, Uri};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+pub(crate) fn factory(uri: &Uri) -> Result<Box<dyn IOutput>> {
+    Ok(Box::new(Output::new(StdoutOutput::new(
+        uri.param_or("format", Format::Json)?,
+    ))))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Format {
+    Raw,
+    Json,
+    Logfmt,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Format::Raw),
+            "json" => Ok(Format::Json),
+            "logfmt" => Ok(Format::Logfmt),
+            _ => Err(format!("unknown stdout output format `{}`", s)),
+        }
+    }
+}
+
+impl Format {
+    // one line without the trailing newline
+    fn line(&self, item: &Item) -> String {
+        let envelope = Envelope::new(item);
+        match self {
+            Format::Raw if item.is_json() => envelope.message().trim_end().to_string(),
+            Format::Raw => item.string().trim_end().to_string(),
+            Format::Json => item.string().trim_end().to_string(),
+            Format::Logfmt => {
+                let message = match item {
+                    Item::JSON(_) => envelope.message().trim_end().to_string(),
+                    Item::Default(line) => line.trim_end().to_string(),
+                };
+                let fields = [
+                    ("ns", envelope.ns()),
+                    ("pod", envelope.pod()),
+                    ("container", envelope.container()),
+                    ("service_name", envelope.service_name()),
+                    ("node", envelope.node()),
+                    ("stream", envelope.stream()),
+                    ("time", envelope.time()),
+                    ("msg", &message),
+                ];
+                fields
+                    .iter()
+                    .filter(|(key, value)| !value.is_empty() || *key == "msg")
+                    .map(|(key, value)| format!("{}={}", key, logfmt_value(value)))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            }
+        }
+    }
+}
+
+fn logfmt_value(value: &str) -> String {
+    if !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c == ' ' || c == '=' || c == '"' || c.is_control())
+    {
+        return value.to_string();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// one record per line on stdout, for sidecars and `| jq` while debugging
+pub(crate) struct StdoutOutput(Format);
+
+impl StdoutOutput {
+    pub(crate) fn new(format: Format) -> Self {
+        Self(format)
+    }
+}
+
+impl IOutput for StdoutOutput {
+    fn write(&mut self, _: &str, item: Item) -> Result<()> {
+        let stdout = io::stdout();
+        let mut w = stdout.lock();
+        writeln!(w, "{}", self.0.line(&item))?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Format;
+    use crate::Uri;
+    use common::Item;
+
+    #[test]
+    fn format_works() {
+        let item = Item::from(
+            r#"{"custom":{"nodeId":"web-0","ns":"default","container":"web","serviceName":"web-svc","nodeName":""},"message":"GET /index \"ok\"\n","stream":"stdout"}"#,
+        );
+        assert_eq!(Format::Raw.line(&item), r#"GET /index "ok""#);
+        assert!(Format::Json.line(&item).starts_with(r#"{"custom":"#));
+        assert_eq!(
+            Format::Logfmt.line(&item),
+            r#"ns=default pod=web-0 container=web service_name=web-svc stream=stdout msg="GET /index \"ok\"""#
+        );
+
+        let item = Item::from("plain line\n");
+        assert_eq!(Format::Raw.line(&item), "plain line");
+        assert_eq!(Format::Logfmt.line(&item), r#"msg="plain line""#);
+        assert_eq!(Format::Logfmt.line(&Item::from("")), r#"msg="""#);
+    }
+
+    #[test]
+    fn format_from_uri() {
+        let uri = Uri::parse("stdout:?format=logfmt").unwrap();
+        assert_eq!(
+            uri.param_or("format", Format::Json).unwrap(),
+            Format::Logfmt
+        );
+        let uri = Uri::parse("stdout://?format=yaml").unwrap();
+        assert!(uri.param_or("format", Format::Json).is_err());
+    }
+}
@@ -0,0 +1,430 @@
+use super::backoff::Backoff;
+use super::{Ack, Envelope, IOutput, Item, Output, QueueFull, Result, Uri};
+use chrono::{DateTime, SecondsFormat, Utc};
+use ringbuf::{Consumer as RConsumer, Producer as RProducer, RingBuffer};
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+const RING_SIZE: usize = 10240;
+const DEFAULT_FACILITY: &str = "local0";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const SEVERITY_ERR: u8 = 3;
+const SEVERITY_INFO: u8 = 6;
+// structured data id, the private enterprise number is the rfc 5424 example one
+const SD_ID: &str = "harvest@32473";
+
+pub(crate) fn factory(uri: &Uri) -> Result<Box<dyn IOutput>> {
+    let cfg = SyslogConfig::from_uri(uri)?;
+    Ok(Box::new(Output::new(SyslogOutput::new(cfg))))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Rfc5424,
+    Rfc3164,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "rfc5424" => Ok(Format::Rfc5424),
+            "rfc3164" => Ok(Format::Rfc3164),
+            _ => Err(format!("unknown syslog format `{}`", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Transport {
+    Udp,
+    // octet counting (rfc 6587), `framing=lf` for newline terminated frames
+    Tcp { octet_counting: bool },
+}
+
+fn facility(name: &str) -> Result<u8> {
+    let code = match name {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        "local0" => 16,
+        "local1" => 17,
+        "local2" => 18,
+        "local3" => 19,
+        "local4" => 20,
+        "local5" => 21,
+        "local6" => 22,
+        "local7" => 23,
+        _ => return Err(format!("unknown syslog facility `{}`", name).into()),
+    };
+    Ok(code)
+}
+
+#[derive(Clone, Debug)]
+struct SyslogConfig {
+    addr: String,
+    transport: Transport,
+    format: Format,
+    facility: u8,
+}
+
+impl SyslogConfig {
+    // channel = syslog+tcp://10.200.100.200:601?format=rfc5424&facility=local0&framing=octet
+    // syslog:// and syslog+udp:// send datagrams, format = rfc5424 | rfc3164
+    fn from_uri(uri: &Uri) -> Result<Self> {
+        if uri.target.to_socket_addrs().is_err() {
+            return Err(format!("syslog output `{}` expects host:port", uri.target).into());
+        }
+        let transport = match uri.scheme.as_str() {
+            "syslog" | "syslog+udp" => Transport::Udp,
+            "syslog+tcp" => Transport::Tcp {
+                octet_counting: match uri.param("framing").unwrap_or("octet") {
+                    "octet" => true,
+                    "lf" => false,
+                    framing => return Err(format!("unknown syslog framing `{}`", framing).into()),
+                },
+            },
+            scheme => return Err(format!("unknown syslog transport `{}`", scheme).into()),
+        };
+        Ok(Self {
+            addr: uri.target.clone(),
+            transport,
+            format: uri.param_or("format", Format::Rfc5424)?,
+            facility: facility(uri.param("facility").unwrap_or(DEFAULT_FACILITY))?,
+        })
+    }
+
+    fn message(&self, item: &Item) -> String {
+        let envelope = Envelope::new(item);
+        let severity = match envelope.stream() {
+            "stderr" => SEVERITY_ERR,
+            _ => SEVERITY_INFO,
+        };
+        let pri = self.facility * 8 + severity;
+        let message = match item {
+            Item::JSON(_) => envelope.message().trim_end().to_string(),
+            Item::Default(line) => line.trim_end().to_string(),
+        };
+        let time = DateTime::parse_from_rfc3339(envelope.time())
+            .map(|t| t.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now());
+
+        match self.format {
+            Format::Rfc5424 => {
+                let mut sd = String::new();
+                for (key, value) in &[
+                    ("ns", envelope.ns()),
+                    ("pod", envelope.pod()),
+                    ("container", envelope.container()),
+                ] {
+                    if !value.is_empty() {
+                        sd.push_str(&format!(" {}=\"{}\"", key, sd_escape(value)));
+                    }
+                }
+                let sd = match sd.is_empty() {
+                    true => "-".to_string(),
+                    false => format!("[{}{}]", SD_ID, sd),
+                };
+                format!(
+                    "<{}>1 {} {} {} - - {} {}",
+                    pri,
+                    time.to_rfc3339_opts(SecondsFormat::Micros, true),
+                    header_field(envelope.node(), 255),
+                    header_field(envelope.service_name(), 48),
+                    sd,
+                    message
+                )
+            }
+            Format::Rfc3164 => {
+                let tag = envelope
+                    .service_name()
+                    .chars()
+                    .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+                    .take(32)
+                    .collect::<String>();
+                format!(
+                    "<{}>{} {} {}: {}",
+                    pri,
+                    time.format("%b %e %H:%M:%S"),
+                    header_field(envelope.node(), 255),
+                    if tag.is_empty() { "harvest" } else { &tag },
+                    message
+                )
+            }
+        }
+    }
+}
+
+// printable us-ascii without spaces, `-` when there is no value
+fn header_field(value: &str, max: usize) -> String {
+    let field = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect::<String>();
+    if field.is_empty() {
+        return "-".to_string();
+    }
+    field
+}
+
+fn sd_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+pub(crate) struct SyslogOutput {
+    cfg: SyslogConfig,
+    queue: RProducer<(String, Ack)>,
+}
+
+impl SyslogOutput {
+    fn new(cfg: SyslogConfig) -> Self {
+        let (p, mut c) = RingBuffer::new(RING_SIZE).split();
+        let mut transmitter = Transmitter {
+            cfg: cfg.clone(),
+            udp: None,
+            tcp: None,
+            backoff: Backoff::new(),
+        };
+        // dialing and writing block, they stay off the outputs lock
+        thread::spawn(move || transmitter.write_out(&mut c));
+        Self { cfg, queue: p }
+    }
+}
+
+// writes the queued messages in order, a message is sent again with backoff
+// until the collector took it and is acked after
+struct Transmitter {
+    cfg: SyslogConfig,
+    udp: Option<UdpSocket>,
+    tcp: Option<TcpStream>,
+    backoff: Backoff,
+}
+
+impl Transmitter {
+    fn write_out(&mut self, cons: &mut RConsumer<(String, Ack)>) {
+        loop {
+            match cons.pop() {
+                Some((message, ack)) => {
+                    self.deliver(&message);
+                    ack.ack();
+                }
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+
+    fn deliver(&mut self, message: &str) {
+        loop {
+            match self.send(message) {
+                Ok(_) => {
+                    self.backoff.reset();
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("syslog {:?} send error: {:?}", self.cfg.addr, e);
+                    thread::sleep(self.backoff.fail());
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, message: &str) -> Result<()> {
+        match self.cfg.transport {
+            Transport::Udp => self.send_udp(message),
+            Transport::Tcp { octet_counting } => {
+                let frame = match octet_counting {
+                    true => format!("{} {}", message.len(), message),
+                    false => format!("{}\n", message),
+                };
+                self.send_tcp(frame.as_bytes())
+            }
+        }
+    }
+
+    fn send_udp(&mut self, message: &str) -> Result<()> {
+        if self.udp.is_none() {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(&self.cfg.addr)?;
+            self.udp = Some(socket);
+        }
+        self.udp.as_ref().unwrap().send(message.as_bytes())?;
+        Ok(())
+    }
+
+    fn send_tcp(&mut self, frame: &[u8]) -> Result<()> {
+        // a broken connection is dialed again once before the write fails
+        for _ in 0..2 {
+            if self.tcp.is_none() {
+                let addr = match self.cfg.addr.to_socket_addrs()?.next() {
+                    Some(addr) => addr,
+                    None => return Err(format!("syslog {:?} not resolved", self.cfg.addr).into()),
+                };
+                self.tcp = Some(TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?);
+            }
+            match self.tcp.as_mut().unwrap().write_all(frame) {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    eprintln!("syslog {:?} write error: {:?}", self.cfg.addr, e);
+                    self.tcp = None;
+                }
+            }
+        }
+        Err(format!("syslog {:?} connection lost", self.cfg.addr).into())
+    }
+}
+
+impl IOutput for SyslogOutput {
+    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
+        self.write_with_ack(channel, item, Ack::none())
+    }
+
+    fn write_with_ack(&mut self, _: &str, item: Item, ack: Ack) -> Result<()> {
+        if self.queue.push((self.cfg.message(&item), ack)).is_err() {
+            return Err(Box::new(QueueFull));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SyslogConfig, SyslogOutput, Transport};
+    use crate::{IOutput, Uri};
+    use common::{Ack, Item};
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream, UdpSocket};
+    use std::sync::{mpsc, Mutex};
+    use std::time::Duration;
+
+    fn item() -> Item {
+        Item::from(
+            r#"{"custom":{"nodeId":"web-0","ns":"default","container":"web","serviceName":"web-svc","nodeName":"node1"},"message":"boom\n","stream":"stderr","time":"2021-03-16T09:05:01.461813Z"}"#,
+        )
+    }
+
+    #[test]
+    fn message_works() {
+        let cfg = SyslogConfig::from_uri(&Uri::parse("syslog://127.0.0.1:514").unwrap()).unwrap();
+        assert_eq!(cfg.transport, Transport::Udp);
+        assert_eq!(
+            cfg.message(&item()),
+            r#"<131>1 2021-03-16T09:05:01.461813Z node1 web-svc - - [harvest@32473 ns="default" pod="web-0" container="web"] boom"#
+        );
+
+        let cfg = SyslogConfig::from_uri(
+            &Uri::parse("syslog+tcp://127.0.0.1:514?format=rfc3164&facility=user").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.message(&item()),
+            "<11>Mar 16 09:05:01 node1 web-svc: boom"
+        );
+        assert!(cfg
+            .message(&Item::from("plain"))
+            .ends_with(" - harvest: plain"));
+
+        for channel in &[
+            "syslog://localhost",
+            "syslog+sctp://127.0.0.1:514",
+            "syslog://127.0.0.1:514?facility=local9",
+            "syslog+tcp://127.0.0.1:514?framing=nul",
+        ] {
+            assert!(SyslogConfig::from_uri(&Uri::parse(channel).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn udp_works() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let channel = format!("syslog+udp://{}", listener.local_addr().unwrap());
+        let mut output =
+            SyslogOutput::new(SyslogConfig::from_uri(&Uri::parse(&channel).unwrap()).unwrap());
+        output.write(&channel, item()).unwrap();
+
+        let mut buf = [0; 1024];
+        let n = listener.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..n]);
+        assert!(message.starts_with("<131>1 "));
+        assert!(message.ends_with(" boom"));
+    }
+
+    // octet counted frames, read until `n` arrived
+    fn frames(stream: &mut TcpStream, n: usize) -> Vec<String> {
+        let mut received = vec![];
+        let mut buf = [0; 1024];
+        loop {
+            let mut frames = vec![];
+            let text = String::from_utf8_lossy(&received).to_string();
+            let mut rest = text.as_str();
+            while let Some(space) = rest.find(' ') {
+                let len = rest[..space].parse::<usize>().unwrap();
+                if rest.len() < space + 1 + len {
+                    break;
+                }
+                frames.push(rest[space + 1..space + 1 + len].to_string());
+                rest = &rest[space + 1 + len..];
+            }
+            if frames.len() >= n {
+                return frames;
+            }
+            let read = stream.read(&mut buf).unwrap();
+            assert!(read > 0);
+            received.extend_from_slice(&buf[..read]);
+        }
+    }
+
+    #[test]
+    fn tcp_octet_counting_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let channel = format!("syslog+tcp://{}", listener.local_addr().unwrap());
+        let mut output =
+            SyslogOutput::new(SyslogConfig::from_uri(&Uri::parse(&channel).unwrap()).unwrap());
+        output.write(&channel, item()).unwrap();
+        output.write(&channel, Item::from("second")).unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let frames = frames(&mut stream, 2);
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].ends_with(" boom"));
+        assert!(frames[1].ends_with(" second"));
+    }
+
+    #[test]
+    fn tcp_acks_after_write() {
+        // nothing listens yet, the message waits in the queue
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let channel = format!("syslog+tcp://{}", addr);
+        let mut output =
+            SyslogOutput::new(SyslogConfig::from_uri(&Uri::parse(&channel).unwrap()).unwrap());
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let ack = Ack::new(move || tx.lock().unwrap().send(()).unwrap());
+        output.write_with_ack(&channel, item(), ack).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        let listener = TcpListener::bind(addr).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        assert!(frames(&mut stream, 1)[0].ends_with(" boom"));
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
@@ -0,0 +1,131 @@
+use common::Result;
+use std::collections::HashMap;
+
+// output channel uri, `scheme:target?k=v&k2=v2` or `scheme://target?k=v`
+// e.g. kafka:topic@10.200.100.200:9092,10.200.100.201:9092
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uri {
+    pub scheme: String,
+    pub target: String,
+    pub query: HashMap<String, String>,
+}
+
+impl Uri {
+    pub fn parse(channel: &str) -> Result<Self> {
+        let (scheme, rest) = match channel.find(':') {
+            Some(index) => (&channel[..index], &channel[index + 1..]),
+            None => return Err(format!("output `{}` has no scheme", channel).into()),
+        };
+        if scheme.is_empty()
+            || !scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            || !scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        {
+            return Err(format!("output `{}` has an invalid scheme", channel).into());
+        }
+        let rest = rest.strip_prefix("//").unwrap_or(rest);
+        let (target, raw_query) = match rest.find('?') {
+            Some(index) => (&rest[..index], &rest[index + 1..]),
+            None => (rest, ""),
+        };
+
+        let mut query = HashMap::new();
+        for pair in raw_query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = match pair.find('=') {
+                Some(index) => (&pair[..index], &pair[index + 1..]),
+                None => (pair, ""),
+            };
+            if key.is_empty() {
+                return Err(format!("output `{}` has an empty query key", channel).into());
+            }
+            query.insert(percent_decode(key)?, percent_decode(value)?);
+        }
+
+        Ok(Self {
+            scheme: scheme.to_ascii_lowercase(),
+            target: target.to_string(),
+            query,
+        })
+    }
+
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.query.get(key).map(|v| v.as_str())
+    }
+
+    // a query parameter parsed into T, `default` when it is absent
+    pub fn param_or<T>(&self, key: &str, default: T) -> Result<T>
+    where
+        T: std::str::FromStr,
+    {
+        match self.param(key) {
+            Some(v) => v
+                .parse::<T>()
+                .map_err(|_| format!("output query `{}={}` is invalid", key, v).into()),
+            None => Ok(default),
+        }
+    }
+}
+
+fn percent_decode(s: &str) -> Result<String> {
+    let bytes = s.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = s
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("invalid percent encoding in `{}`", s))?;
+                res.push(hex);
+                i += 3;
+            }
+            b'+' => {
+                res.push(b' ');
+                i += 1;
+            }
+            b => {
+                res.push(b);
+                i += 1;
+            }
+        }
+    }
+    Ok(String::from_utf8(res)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Uri;
+
+    #[test]
+    fn parse_works() {
+        let uri = Uri::parse("kafka:test@10.200.100.200:9092,10.200.100.201:9092").unwrap();
+        assert_eq!(uri.scheme, "kafka");
+        assert_eq!(uri.target, "test@10.200.100.200:9092,10.200.100.201:9092");
+        assert!(uri.query.is_empty());
+
+        let uri = Uri::parse("kafka://test@localhost:9092?acks=all&client_id=harvest%20a").unwrap();
+        assert_eq!(uri.target, "test@localhost:9092");
+        assert_eq!(uri.param("acks"), Some("all"));
+        assert_eq!(uri.param("client_id"), Some("harvest a"));
+        assert_eq!(uri.param_or("batch", 5).unwrap(), 5);
+
+        let uri = Uri::parse("file:///data/harvest/{ns}/{pod}.log").unwrap();
+        assert_eq!(uri.scheme, "file");
+        assert_eq!(uri.target, "/data/harvest/{ns}/{pod}.log");
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(Uri::parse("fake_output").is_err());
+        assert!(Uri::parse(":abc").is_err());
+        assert!(Uri::parse("1kafka:abc").is_err());
+        assert!(Uri::parse("kafka:t@h?=1").is_err());
+        assert!(Uri::parse("kafka:t@h?a=%zz").is_err());
+        assert!(Uri::parse("kafka:t@h?batch=x")
+            .unwrap()
+            .param_or("batch", 5)
+            .is_err());
+    }
+}
@@ -1,12 +1,13 @@
 use common::Result;
-use db::Pod;
+use db::{LogFormat, Pod};
 use event::{Dispatch, Listener};
 use notify::{raw_watcher, RawEvent, RecursiveMode, Watcher};
 use std::collections::{hash_map::DefaultHasher, HashMap};
 use std::hash::{Hash, Hasher};
+use std::path::Path;
 use std::sync::mpsc::channel;
 use std::sync::{Arc, RwLock};
-use strum::AsRefStr;
+use strum::{AsRefStr, EnumString};
 use walkdir::WalkDir;
 mod config_v2;
 use config_v2::JSONConfig;
@@ -21,6 +22,17 @@ pub enum PathEvent {
     Write,
 }
 
+// how container logs are laid out on the node
+#[derive(Debug, AsRefStr, EnumString, Clone, PartialEq)]
+pub enum DiscoveryMode {
+    // <docker_dir>/containers/<id>/config.v2.json and <id>-json.log
+    #[strum(serialize = "docker")]
+    Docker,
+    // containerd/cri-o: /var/log/pods/<ns>_<pod>_<uid>/<container>/N.log
+    #[strum(serialize = "cri")]
+    CRI,
+}
+
 pub trait GetPathEventInfo {
     fn get(&self) -> &PathEventInfo;
 }
@@ -35,7 +47,9 @@ pub struct PathEventInfo {
     pub ns: String,
     pub pod_name: String,
     pub container_name: String,
+    pub pod_uid: String,
     pub path: String,
+    pub format: LogFormat,
     pub ips: Vec<String>,
 }
 
@@ -46,7 +60,9 @@ impl Default for PathEventInfo {
             ns: "".to_string(),
             pod_name: "".to_string(),
             container_name: "".to_string(),
+            pod_uid: "".to_string(),
             path: "".to_string(),
+            format: LogFormat::Raw,
             ips: vec![],
         }
     }
@@ -72,6 +88,7 @@ impl PathEventInfo {
             pod_name: self.pod_name.clone(),
             container: self.container_name.clone(),
             path: self.path.clone(),
+            format: self.format,
             ..Default::default()
         }
     }
@@ -95,17 +112,48 @@ fn docker_config_file_type(path: &str) -> DockerConfigFileType {
     DockerConfigFileType::Unknow
 }
 
+// /var/log/pods/<ns>_<pod>_<uid>/<container>/N.log
+fn cri_path_to_pei(path: &str) -> Option<PathEventInfo> {
+    let log = Path::new(path);
+    let file_name = log.file_name()?.to_str()?;
+    if !file_name.ends_with(".log") {
+        return None;
+    }
+    let container_dir = log.parent()?;
+    let container_name = container_dir.file_name()?.to_str()?;
+    let pod_dir = container_dir.parent()?.file_name()?.to_str()?;
+
+    let ns_pod_uid = pod_dir.splitn(3, '_').collect::<Vec<&str>>();
+    if ns_pod_uid.len() != 3 || ns_pod_uid.iter().any(|s| s.is_empty()) {
+        return None;
+    }
+    Some(PathEventInfo {
+        ns: ns_pod_uid[0].to_string(),
+        pod_name: ns_pod_uid[1].to_string(),
+        pod_uid: ns_pod_uid[2].to_string(),
+        container_name: container_name.to_string(),
+        path: path.to_string(),
+        format: LogFormat::CRI,
+        ..Default::default()
+    })
+}
+
 type Cache = Arc<Vec<RwLock<HashMap<String, Option<JSONConfig>>>>>;
 
 pub struct AutoScanner {
     namespace: String,
     docker_dir: String,
+    mode: DiscoveryMode,
     event_dispatch: Dispatch<PathEventInfo>,
     cache: Cache,
 }
 
 impl AutoScanner {
     pub fn new(namespace: String, docker_dir: String) -> Self {
+        Self::new_with_mode(namespace, docker_dir, DiscoveryMode::Docker)
+    }
+
+    pub fn new_with_mode(namespace: String, docker_dir: String, mode: DiscoveryMode) -> Self {
         let len = 2;
         let mut cache: Vec<RwLock<HashMap<String, Option<JSONConfig>>>> = Vec::with_capacity(len);
         for _i in 0..len {
@@ -114,6 +162,7 @@ impl AutoScanner {
         Self {
             namespace,
             docker_dir,
+            mode,
             event_dispatch: Dispatch::<PathEventInfo>::new(),
             cache: Arc::new(cache),
         }
@@ -182,6 +231,27 @@ impl AutoScanner {
         }
     }
 
+    // resolve the pod metadata of a log file, docker mode via the cached
+    // config.v2.json, cri mode from the directory structure
+    fn lookup(&self, path: &str) -> Option<PathEventInfo> {
+        match self.mode {
+            DiscoveryMode::Docker => match self.get(path) {
+                Some(cfg) => Some(Self::config_to_pei(
+                    &cfg.get_service_name(),
+                    &cfg.get_ns(),
+                    &cfg.get_pod_name(),
+                    &cfg.get_container_name(),
+                    &cfg.log_path,
+                )),
+                None => None,
+            },
+            DiscoveryMode::CRI => match cri_path_to_pei(path) {
+                Some(pei) if pei.ns == self.namespace => Some(pei),
+                _ => None,
+            },
+        }
+    }
+
     pub fn append_close_event_handle<L>(&mut self, l: L)
     where
         L: Listener<PathEventInfo> + Send + Sync + 'static,
@@ -230,6 +300,7 @@ impl AutoScanner {
             pod_name: pod_name.to_string(),
             container_name: container_name.to_string(),
             path: log_path.to_string(),
+            format: LogFormat::DockerJSON,
             ..Default::default()
         }
     }
@@ -240,6 +311,9 @@ impl AutoScanner {
     }
 
     pub fn prepare(&self) -> Result<Vec<PathEventInfo>> {
+        if self.mode == DiscoveryMode::CRI {
+            return self.prepare_cri();
+        }
         let mut result = vec![];
         for entry in WalkDir::new(self.docker_dir.clone()) {
             let entry = entry?;
@@ -275,6 +349,20 @@ impl AutoScanner {
         Ok(result)
     }
 
+    fn prepare_cri(&self) -> Result<Vec<PathEventInfo>> {
+        let mut result = vec![];
+        for entry in WalkDir::new(self.docker_dir.clone()) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            if let Some(pei) = self.lookup(entry.path().to_str().unwrap()) {
+                result.push(pei);
+            }
+        }
+        Ok(result)
+    }
+
     pub fn watch_start(&mut self) -> Result<()> {
         let (tx, rx) = channel();
         let mut watcher = raw_watcher(tx).unwrap();
@@ -290,19 +378,11 @@ impl AutoScanner {
             match op {
                 notify::Op::CREATE => match docker_config_file_type(path) {
                     DockerConfigFileType::ConfigV2 => self.insert(path, JSONConfig::from(path)),
-                    DockerConfigFileType::Log => match self.get(path) {
-                        Some(cfg) => {
-                            let pei = Self::config_to_pei(
-                                &cfg.get_service_name(),
-                                &cfg.get_ns(),
-                                &cfg.get_pod_name(),
-                                &cfg.get_container_name(),
-                                &cfg.log_path,
-                            );
+                    DockerConfigFileType::Log => {
+                        if let Some(pei) = self.lookup(path) {
                             self.dispatch_create_event(&pei)
                         }
-                        _ => {}
-                    },
+                    }
                     _ => continue,
                 },
                 notify::Op::WRITE => match docker_config_file_type(path) {
@@ -327,15 +407,8 @@ impl AutoScanner {
                             DockerConfigFileType::ConfigV2 => {
                                 self.insert(path, JSONConfig::from(path))
                             }
-                            DockerConfigFileType::Log => match self.get(path) {
-                                Some(cfg) => {
-                                    let pei = Self::config_to_pei(
-                                        &cfg.get_service_name(),
-                                        &cfg.get_ns(),
-                                        &cfg.get_pod_name(),
-                                        &cfg.get_container_name(),
-                                        &cfg.log_path,
-                                    );
+                            DockerConfigFileType::Log => match self.lookup(path) {
+                                Some(pei) => {
                                     self.dispatch_create_event(&pei);
                                     self.dispatch_write_event(&pei)
                                 }
@@ -372,8 +445,10 @@ impl AutoScanner {
 
 #[cfg(test)]
 mod tests {
-    use crate::{AutoScanner, GetDebug, PathEvent};
+    use crate::{cri_path_to_pei, AutoScanner, DiscoveryMode, GetDebug, PathEvent};
+    use db::LogFormat;
     use event::Listener;
+    use std::fs;
 
     #[test]
     fn it_works() {
@@ -397,4 +472,42 @@ mod tests {
         assert_eq!(PathEvent::Create.as_ref(), "NeedOpen");
         assert_eq!(PathEvent::Write.as_ref(), "NeedWrite");
     }
+
+    #[test]
+    fn cri_path_works() {
+        let pei = cri_path_to_pei(
+            "/var/log/pods/finance-dev_sky-fcms-web-ui-0-b-0_c7621e69-de2b-4a5c-b439-6e3021dba432/web/0.log",
+        )
+        .unwrap();
+        assert_eq!(pei.ns, "finance-dev");
+        assert_eq!(pei.pod_name, "sky-fcms-web-ui-0-b-0");
+        assert_eq!(pei.pod_uid, "c7621e69-de2b-4a5c-b439-6e3021dba432");
+        assert_eq!(pei.container_name, "web");
+        assert_eq!(pei.to_pod().format, LogFormat::CRI);
+
+        assert!(cri_path_to_pei("/var/log/pods/broken/web/0.log").is_none());
+        assert!(cri_path_to_pei("/var/log/pods/a_b_c/web/0.log.20210316-090501").is_none());
+    }
+
+    #[test]
+    fn cri_prepare_works() {
+        let root = std::env::temp_dir().join("harvest_scan_cri_prepare");
+        let _ = fs::remove_dir_all(&root);
+        for dir in &["finance-dev_web-0_uid0/web", "default_other-0_uid1/other"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("0.log"), "").unwrap();
+        }
+
+        let auto_scanner = AutoScanner::new_with_mode(
+            "finance-dev".into(),
+            root.to_str().unwrap().into(),
+            "cri".parse::<DiscoveryMode>().unwrap(),
+        );
+        let res = auto_scanner.prepare().unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].pod_name, "web-0");
+        assert_eq!(res[0].container_name, "web");
+
+        let _ = fs::remove_dir_all(&root);
+    }
 }
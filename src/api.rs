@@ -1,4 +1,5 @@
 use super::{run_task, stop_task, tasks_json, Task};
+use db::Multiline;
 use rocket::get;
 use rocket_contrib::json::JsonValue;
 use serde::{Deserialize, Serialize};
@@ -32,7 +33,17 @@ pub(crate) fn recv_tasks(addr: &str, node_name: &str) {
             continue;
         }
 
-        output::registry_output(request.output);
+        let mut registered = true;
+        for channel in request.channels() {
+            if let Err(e) = output::registry_output(channel) {
+                eprintln!("registry output {:?} error: {:?}", channel, e);
+                registered = false;
+                break;
+            }
+        }
+        if !registered {
+            continue;
+        }
 
         for task in request.to_pod_tasks() {
             if request.op == RUN {
@@ -56,12 +67,19 @@ pub(crate) struct RequestPod<'a> {
 
 //{"op":"run","ns":"default","service_name":"xx_service","rules":"","output":"fake_output","pods":[{"node":"node1","pod":"xx","ips":["127.0.0.1"],"offset":0}]}
 //{"op":"stop","ns":"default","service_name":"xx_service","rules":"","output":"fake_output","pods":[{"node":"node1","pod":"xx","ips":["127.0.0.1"],"offset":0}]}
+//{"op":"run","ns":"default","service_name":"xx_service","rules":"","output":"kafka:logs-{ns}@10.200.100.200:9092","outputs":["file:///data/audit/{ns}/{pod}.log"],"pods":[{"node":"node1","pod":"xx","ips":["127.0.0.1"],"offset":0}]}
+//{"op":"run","ns":"default","service_name":"xx_service","rules":"","multiline":{"continuation":"^(\\s|Caused by:)","max_lines":500,"timeout_ms":1000},"output":"fake_output","pods":[{"node":"node1","pod":"xx","ips":["127.0.0.1"],"offset":0}]}
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct ApiServerRequest<'a> {
     op: &'a str,
     pub(crate) ns: &'a str,
     pub(crate) output: &'a str,
+    // more channels every line also goes to, e.g. a local file next to kafka
+    #[serde(default, borrow)]
+    pub(crate) outputs: Vec<&'a str>,
     pub(crate) rules: &'a str,
+    #[serde(default)]
+    pub(crate) multiline: Option<Multiline>,
     pub(crate) service_name: &'a str,
     pub(crate) pods: Vec<RequestPod<'a>>,
 }
@@ -74,8 +92,10 @@ impl<'a> ApiServerRequest<'a> {
                 let mut task = Task::from(req_pod.clone());
                 task.pod.ns = self.ns.to_string();
                 task.pod.output = self.output.to_string();
+                task.pod.outputs = self.outputs.iter().map(|o| o.to_string()).collect();
                 task.pod.service_name = self.service_name.to_string();
                 task.pod.filter = self.rules.to_string();
+                task.pod.multiline = self.multiline.clone();
                 task
             })
             .collect::<Vec<Task>>()
@@ -83,6 +103,10 @@ impl<'a> ApiServerRequest<'a> {
 }
 
 impl<'a> ApiServerRequest<'a> {
+    pub fn channels(&self) -> Vec<&'a str> {
+        db::channels(self.output, self.outputs.iter().copied())
+    }
+
     pub fn has_node_events(&self, node_name: &str) -> bool {
         for pod in self.pods.iter() {
             if pod.node == node_name {
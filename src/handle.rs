@@ -43,6 +43,21 @@ where
     }
 }
 
+pub(crate) struct DBTruncateEvent();
+impl<T> Listener<T> for DBTruncateEvent
+where
+    T: Clone + GetPod,
+{
+    fn handle(&self, t: T) {
+        if let Some(pod) = t.get() {
+            eprintln!(
+                "warning: frw {:?} is shorter than offset {}, truncated, collect from 0",
+                pod.path, pod.offset
+            );
+        }
+    }
+}
+
 pub(crate) struct ScannerCreateEvent(pub Arc<Mutex<FileReaderWriter>>);
 impl<T> Listener<T> for ScannerCreateEvent
 where
@@ -19,8 +19,8 @@ pub(crate) use api::*;
 
 pub use common::{new_arc_rwlock, Result};
 pub(crate) use handle::{
-    DBCloseEvent, DBOpenEvent, ScannerCloseEvent, ScannerCreateEvent, ScannerWriteEvent,
-    TaskRunEvent, TaskStopEvent,
+    DBCloseEvent, DBOpenEvent, DBTruncateEvent, ScannerCloseEvent, ScannerCreateEvent,
+    ScannerWriteEvent, TaskRunEvent, TaskStopEvent,
 };
 pub use server::Harvest;
 
@@ -70,6 +70,7 @@ impl<'a> From<RequestPod<'a>> for Task {
         Self {
             pod: Pod {
                 pod_name: a.pod.to_string(),
+                node_name: a.node.to_string(),
                 offset: a.offset,
                 ips,
                 ..Default::default()
@@ -168,10 +169,17 @@ impl TaskStorage {
                                 continue;
                             }
                         };
+                        let request = task.pod.clone();
                         for (_, mut pod) in
-                            db::get_slice_with_ns_pod(&task.pod.ns, &task.pod.pod_name)
+                            db::get_slice_with_ns_pod(&request.ns, &request.pod_name)
                         {
-                            pod.merge_with(&task.pod);
+                            // offset 0 from the api server means no preference, keep the
+                            // offset collected so far or restored from the checkpoint
+                            let committed = pod.offset;
+                            pod.merge_with(&request);
+                            if request.offset == 0 {
+                                pod.offset = committed;
+                            }
                             pod.upload();
                             pod.set_state_run();
 
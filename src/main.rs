@@ -19,12 +19,29 @@ pub struct ServerOptions {
     // short and long flags (-h, --node) will be deduced from the field's name
     #[structopt(short = "h", long)]
     host: String,
+
+    // log layout under docker-dir: docker (config.v2.json) or cri (/var/log/pods)
+    #[structopt(short = "r", long, default_value = "docker")]
+    runtime: String,
+
+    // collected offsets are persisted here and restored on restart
+    #[structopt(short = "c", long, default_value = "/var/lib/harvest/checkpoint.json")]
+    checkpoint: String,
 }
 // cargo run -- --namespace default --docker_dir /var/log/container --api-server http://localhost:9999/ --host node1
+// cargo run -- --namespace default --docker-dir /var/log/pods --runtime cri --api-server http://localhost:9999/ --host node1
 
 fn main() -> Result<()> {
     let opt = ServerOptions::from_args();
     println!("recv args {:?}", opt);
 
-    Harvest::new(&opt.namespace, &opt.docker_dir, &opt.api_server, &opt.host).start()
+    Harvest::new(
+        &opt.namespace,
+        &opt.docker_dir,
+        &opt.api_server,
+        &opt.host,
+        &opt.runtime,
+        &opt.checkpoint,
+    )
+    .start()
 }
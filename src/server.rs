@@ -4,13 +4,18 @@ use common::new_arc_mutex;
 use file::FileReaderWriter;
 use rocket::config::{Config, Environment};
 use rocket::routes;
-use scan::AutoScanner;
+use scan::{AutoScanner, DiscoveryMode};
+use std::time::Duration;
+
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
 
 pub struct Harvest<'a> {
     node_name: &'a str,
     namespace: &'a str,
     docker_dir: &'a str,
     api_server_addr: &'a str,
+    runtime: &'a str,
+    checkpoint: &'a str,
 }
 
 impl<'a> Harvest<'a> {
@@ -19,19 +24,32 @@ impl<'a> Harvest<'a> {
         docker_dir: &'a str,
         api_server_addr: &'a str,
         node_name: &'a str,
+        runtime: &'a str,
+        checkpoint: &'a str,
     ) -> Self {
         Self {
             namespace,
             docker_dir,
             node_name,
             api_server_addr,
+            runtime,
+            checkpoint,
         }
     }
 
     pub fn start(&mut self) -> Result<()> {
-        let scanner = new_arc_rwlock(AutoScanner::new(
+        db::open_checkpoint(self.checkpoint, CHECKPOINT_INTERVAL)?;
+        ctrlc::set_handler(|| {
+            if let Err(e) = db::checkpoint() {
+                eprintln!("checkpoint flush on shutdown failed, error: {:?}", e);
+            }
+            std::process::exit(0);
+        })?;
+
+        let scanner = new_arc_rwlock(AutoScanner::new_with_mode(
             String::from(self.namespace),
             String::from(self.docker_dir),
+            self.runtime.parse::<DiscoveryMode>()?,
         ));
 
         let frw = new_arc_mutex(FileReaderWriter::new(0));
@@ -43,9 +61,10 @@ impl<'a> Harvest<'a> {
             scan.append_close_event_handle(ScannerCloseEvent());
         }
 
-        // registry db open/close events
+        // registry db open/close/truncate events
         db::registry_open_event_listener(DBOpenEvent(frw.clone()));
         db::registry_close_event_listener(DBCloseEvent(frw.clone()));
+        db::registry_truncate_event_listener(DBTruncateEvent());
 
         // registry task run/stop event handle
         registry_task_run_event_listener(TaskRunEvent(frw.clone()));
@@ -70,9 +89,11 @@ impl<'a> Harvest<'a> {
                 }
             };
 
-            // add to local MemDatabase
+            // add to local MemDatabase, continue from the offsets before restart
             for item in res.iter() {
-                db::insert(&item.to_pod())
+                let mut pod = item.to_pod();
+                db::restore_offset(&mut pod);
+                db::insert(&pod)
             }
 
             if let Err(e) = scan.watch_start() {
@@ -100,6 +121,6 @@ impl<'a> Harvest<'a> {
         for _ in tasks {}
 
         task_close();
-        Ok(())
+        db::checkpoint()
     }
 }